@@ -1,9 +1,57 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use std::fs;
+use std::io::Write;
 
-use lib::bench;
+use lib::{bench, ledger, mmap_reader, parallel_reader, reader};
+
+/*
+ * A file with a bit of everything in it, so both readers have to deal with
+ * every transaction type and an empty amount now and then.
+ * */
+fn generate_input(rows: u32) -> String {
+    let path = std::env::temp_dir().join(format!("toy_payment_engine_bench_{}.csv", rows));
+    let mut file = fs::File::create(&path).unwrap();
+    writeln!(file, "type,client,tx,amount").unwrap();
+    (0..rows).for_each(|tx| {
+        let client = tx % 1000;
+        let _ = match tx % 10 {
+            0..=5 => writeln!(file, "deposit,{},{},{}.{}", client, tx, tx % 100, tx % 9999),
+            6 | 7 => writeln!(file, "withdrawal,{},{},1.5", client, tx),
+            8 => writeln!(file, "dispute,{},{},", client, tx - 8),
+            _ => writeln!(file, "resolve,{},{},", client, tx - 9),
+        };
+    });
+    path.to_string_lossy().into_owned()
+}
 
 pub fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("bench 20", |b| b.iter(|| bench("bench.csv")));
+
+    let input = generate_input(1_000_000);
+    let mut group = c.benchmark_group("reader 1M rows");
+    group.sample_size(10);
+    group.bench_function("csv + serde", |b| {
+        b.iter(|| reader::read_file_from_path(&input))
+    });
+    group.bench_function("memory-mapped", |b| {
+        b.iter(|| mmap_reader::read_file_from_path(&input))
+    });
+    group.bench_function("parallel, 1 thread", |b| {
+        b.iter(|| parallel_reader::read_file_from_path(&input, 1))
+    });
+    let threads = parallel_reader::default_threads();
+    group.bench_function(format!("parallel, {} threads", threads), |b| {
+        b.iter(|| parallel_reader::read_file_from_path(&input, threads))
+    });
+    group.finish();
+
+    let transactions = reader::read_file_from_path(&input).unwrap();
+    let mut group = c.benchmark_group("ledger 1M transactions");
+    group.sample_size(10);
+    group.bench_function("parse_transactions", |b| {
+        b.iter(|| ledger::parse_transactions(&transactions))
+    });
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,24 +1,240 @@
-use crate::currency::{from_float, to_float_string, Currency};
-use serde::Serialize;
+use crate::currency::{
+    add, from_float, from_float_string, safe_subtract_verbose, to_float_string, unsafe_subtract,
+    Currency,
+};
+use crate::error::UnsettledReason;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
-#[derive(Eq, PartialEq, Debug, Serialize)]
+/*
+ * The total isn't kept, it's always what's available plus what's held. The
+ * fields can only be changed through the methods below, and none of them can
+ * take what's held below 0. Available can go below 0 though: a deposit that's
+ * already been spent can still be disputed, unless the ledger is told
+ * otherwise, see `OverdraftPolicy`, and a client with a credit line can
+ * withdraw more than they have. Anything below 0 is credit they're using,
+ * whichever way it got there, and deposits pay that off before anything else.
+ *
+ * The receivable is what the client owes for disputes that found less
+ * available than they were for. It isn't part of the total, it's money that
+ * isn't there.
+ *
+ * The methods that can fail leave the account as it was when they do.
+ * */
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(into = "Row", try_from = "Row")]
 pub struct Account {
-    pub client: u16,
-    #[serde(serialize_with = "to_float_string")]
-    pub available: Currency,
-    #[serde(serialize_with = "to_float_string")]
-    pub held: Currency,
-    #[serde(serialize_with = "to_float_string")]
-    pub total: Currency,
-    pub locked: bool,
+    client: u16,
+    available: Currency,
+    held: Currency,
+    receivable: Currency,
+    locked: bool,
 }
 
 pub fn new_from_client_id(client: u16) -> Account {
     Account {
-        client: client,
+        client,
         available: from_float(0.0),
         held: from_float(0.0),
-        total: from_float(0.0),
+        receivable: from_float(0.0),
         locked: false,
     }
 }
+
+/*
+ * For accounts that were written somewhere and read back. Nothing that's
+ * held or owed below 0 comes back.
+ * */
+pub fn restore(
+    client: u16,
+    available: Currency,
+    held: Currency,
+    receivable: Currency,
+    locked: bool,
+) -> Option<Account> {
+    if held.0 < 0 || receivable.0 < 0 {
+        return None;
+    }
+    Some(Account {
+        client,
+        available,
+        held,
+        receivable,
+        locked,
+    })
+}
+
+impl Account {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn available(&self) -> Currency {
+        self.available
+    }
+
+    pub fn held(&self) -> Currency {
+        self.held
+    }
+
+    pub fn total(&self) -> Currency {
+        add(self.available, self.held)
+    }
+
+    pub fn receivable(&self) -> Currency {
+        self.receivable
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /* Money coming in */
+    pub fn credit(&mut self, amount: Currency) {
+        self.available = add(self.available, amount);
+    }
+
+    /*
+     * Money going out, only as much as is available, and whatever's left of
+     * the credit limit on top of that.
+     * */
+    pub fn debit(
+        &mut self,
+        amount: Currency,
+        credit_limit: Currency,
+    ) -> Result<(), UnsettledReason> {
+        self.available = safe_subtract_verbose(add(self.available, credit_limit), amount)
+            .map(|left| unsafe_subtract(left, credit_limit))
+            .ok_or(UnsettledReason::InsufficientFunds)?;
+        Ok(())
+    }
+
+    /* How much of a credit line is drawn */
+    pub fn credit_used(&self) -> Currency {
+        Currency((-self.available.0).max(0))
+    }
+
+    /* Moves money from available to held, even if that isn't there anymore */
+    pub fn hold(&mut self, amount: Currency) {
+        self.available = unsafe_subtract(self.available, amount);
+        self.held = add(self.held, amount);
+    }
+
+    /* Same, but only if it's all still there */
+    pub fn hold_available(&mut self, amount: Currency) -> Result<(), UnsettledReason> {
+        safe_subtract_verbose(self.available, amount).ok_or(UnsettledReason::InsufficientFunds)?;
+        self.hold(amount);
+        Ok(())
+    }
+
+    /*
+     * Holds whatever's available, up to the amount, and adds the rest to the
+     * receivable. Returns that rest, the shortfall.
+     * */
+    pub fn hold_owing(&mut self, amount: Currency) -> Currency {
+        let held = Currency(self.available.0.clamp(0, amount.0.max(0)));
+        let shortfall = unsafe_subtract(amount, held);
+        self.hold(held);
+        self.receivable = add(self.receivable, shortfall);
+        shortfall
+    }
+
+    /* Moves money that's held back to available */
+    pub fn release(&mut self, amount: Currency) -> Result<(), UnsettledReason> {
+        self.held =
+            safe_subtract_verbose(self.held, amount).ok_or(UnsettledReason::InsufficientFunds)?;
+        self.available = add(self.available, amount);
+        Ok(())
+    }
+
+    /*
+     * Undoes `hold_owing`: the amount less the shortfall was held, and the
+     * shortfall isn't owed anymore.
+     * */
+    pub fn release_owing(
+        &mut self,
+        amount: Currency,
+        shortfall: Currency,
+    ) -> Result<(), UnsettledReason> {
+        let receivable = safe_subtract_verbose(self.receivable, shortfall)
+            .ok_or(UnsettledReason::InsufficientFunds)?;
+        let mut released = *self;
+        released.release(unsafe_subtract(amount, shortfall))?;
+        *self = Account {
+            receivable,
+            ..released
+        };
+        Ok(())
+    }
+
+    /*
+     * Takes money that's held out of the account for good, and locks it. The
+     * total can't go below minus the credit limit here, which it would if what
+     * was disputed was already spent, and more than the credit line covers.
+     * */
+    pub fn reverse(
+        &mut self,
+        amount: Currency,
+        credit_limit: Currency,
+    ) -> Result<(), UnsettledReason> {
+        let held =
+            safe_subtract_verbose(self.held, amount).ok_or(UnsettledReason::InsufficientFunds)?;
+        safe_subtract_verbose(add(self.total(), credit_limit), amount)
+            .ok_or(UnsettledReason::InsufficientFunds)?;
+        self.held = held;
+        self.locked = true;
+        Ok(())
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+}
+
+/*
+ * What's written out, total included. Deserializing is only used to read back
+ * our own output, so a total that doesn't add up means it isn't. The
+ * receivable is left out, see `writer` for when it's written.
+ * */
+#[derive(Serialize, Deserialize)]
+struct Row {
+    client: u16,
+    #[serde(
+        serialize_with = "to_float_string",
+        deserialize_with = "from_float_string"
+    )]
+    available: Currency,
+    #[serde(
+        serialize_with = "to_float_string",
+        deserialize_with = "from_float_string"
+    )]
+    held: Currency,
+    #[serde(
+        serialize_with = "to_float_string",
+        deserialize_with = "from_float_string"
+    )]
+    total: Currency,
+    locked: bool,
+}
+
+impl From<Account> for Row {
+    fn from(account: Account) -> Row {
+        Row {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked,
+        }
+    }
+}
+
+impl TryFrom<Row> for Account {
+    type Error = String;
+
+    fn try_from(row: Row) -> Result<Account, String> {
+        restore(row.client, row.available, row.held, Currency(0), row.locked)
+            .filter(|account| account.total() == row.total)
+            .ok_or_else(|| format!("account {} doesn't add up", row.client))
+    }
+}
@@ -0,0 +1,143 @@
+use crate::error::Csv;
+use crate::follow::read_headers;
+use crate::transaction::Transaction;
+use crate::wal::crc32;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+ * How far into which input a ledger got. This is saved along with the ledger,
+ * in the snapshot and in every record of the write-ahead log, so it can never
+ * say more or less than what the ledger actually has. Running the same input
+ * again skips everything up to it, so a row is only ever applied once, and a
+ * file that grew in the meantime only has its new rows applied.
+ *
+ * The source is the canonical path of the file. A different file could end up
+ * at the same path, so the first few KB up to the offset are checksummed as
+ * well. Offsets are in bytes, and always right after a newline. Every row of
+ * a file gets a checkpoint, so they all share the one source.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Checkpoint {
+    pub source: Arc<str>,
+    pub offset: u64,
+    pub rows: u64,
+    pub fingerprint: u32,
+}
+
+const FINGERPRINT_BYTES: usize = 4096;
+
+fn read_error(e: std::io::Error) -> Csv {
+    Csv::FileReadError(format!("Error reading file: {:?}", e))
+}
+
+pub fn source(path: &str) -> Result<Arc<str>, Csv> {
+    fs::canonicalize(Path::new(path))
+        .map(|path| path.to_string_lossy().into())
+        .map_err(read_error)
+}
+
+fn fingerprint(bytes: &[u8], offset: u64) -> u32 {
+    crc32(&bytes[..(offset as usize).min(FINGERPRINT_BYTES)])
+}
+
+/*
+ * Whatever's left of an input after a checkpoint. Every transaction comes with
+ * the checkpoint right after it. Anything after the last newline is most likely
+ * still being written, so it's left for the next run, like `follow` does.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub struct Remaining {
+    pub skipped: u64,
+    pub transactions: Vec<(Transaction, Checkpoint)>,
+    pub unfinished: usize,
+}
+
+/*
+ * A checkpoint from another source is ignored, the input is read from the
+ * start. One from this source that doesn't match the file anymore means the
+ * file was replaced or rewritten, and it's not safe to either skip or apply
+ * anything.
+ * */
+pub fn read_remaining(path: &str, from: Option<&Checkpoint>) -> Result<Remaining, Csv> {
+    let source = source(path)?;
+    let bytes = fs::read(path).map_err(read_error)?;
+    let start = match from {
+        Some(from) if from.source == source => {
+            if from.offset > bytes.len() as u64
+                || fingerprint(&bytes, from.offset) != from.fingerprint
+            {
+                return Err(Csv::FileReadError(format!(
+                    "{} changed since it was last read up to byte {}",
+                    path, from.offset
+                )));
+            }
+            from.clone()
+        }
+        _ => Checkpoint {
+            source,
+            offset: 0,
+            rows: 0,
+            fingerprint: fingerprint(&bytes, 0),
+        },
+    };
+
+    let complete = bytes
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |last_newline| last_newline + 1)
+        .max(start.offset as usize);
+    let mut headers = match start.offset {
+        0 => None,
+        _ => read_headers(&mut Cursor::new(&bytes))?,
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(&bytes[start.offset as usize..complete]);
+    let mut record = csv::StringRecord::new();
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    let mut rows = start.rows;
+    /* Past the first few KB, every row has the same one */
+    let whole = fingerprint(&bytes, bytes.len() as u64);
+    let fingerprint = |offset: u64| match offset {
+        offset if offset >= FINGERPRINT_BYTES as u64 => whole,
+        offset => fingerprint(&bytes, offset),
+    };
+    while reader
+        .read_record(&mut record)
+        .map_err(|e| Csv::ParseError(vec![format!("{:?}", e)]))?
+    {
+        let offset = start.offset + reader.position().byte();
+        match &headers {
+            None => headers = Some(record.clone()),
+            Some(headers) => {
+                rows += 1;
+                match record.deserialize::<Transaction>(Some(headers)) {
+                    Ok(transaction) => transactions.push((
+                        transaction,
+                        Checkpoint {
+                            source: start.source.clone(),
+                            offset,
+                            rows,
+                            fingerprint: fingerprint(offset),
+                        },
+                    )),
+                    Err(e) => errors.push(format!("{:?}", e)),
+                }
+            }
+        }
+    }
+
+    /* Same as `reader::read_file_from_path`, one bad row and nothing is applied */
+    if !errors.is_empty() {
+        return Err(Csv::ParseError(errors));
+    }
+    Ok(Remaining {
+        skipped: start.rows,
+        transactions,
+        unfinished: bytes.len() - complete,
+    })
+}
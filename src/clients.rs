@@ -0,0 +1,99 @@
+use crate::currency::{from_float_string, from_optional_float_string, Currency};
+use crate::error::Csv;
+use crate::limits::WithdrawalLimits;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/*
+ * What's been agreed on with a client, from a CSV with a row per client:
+ *
+ *   client,credit_limit,max_withdrawal,max_withdrawal_count,max_withdrawal_total
+ *   7,100.0,50.0,,200.0
+ *
+ * Clients that aren't in it get the defaults. A credit limit of 100 lets
+ * withdrawals take available down to -100. Withdrawal limits that are left
+ * empty are whatever they are for everyone, see `Config::withdrawal_limits`.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ClientConfig {
+    pub credit_limit: Currency,
+    pub withdrawal_limits: WithdrawalLimits,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            credit_limit: Currency(0),
+            withdrawal_limits: WithdrawalLimits::default(),
+        }
+    }
+}
+
+fn zero() -> Currency {
+    Currency(0)
+}
+
+/* Columns can be left out, or left empty, for the defaults */
+#[derive(Debug, Deserialize)]
+struct Row {
+    client: u16,
+    #[serde(default = "zero", deserialize_with = "from_float_string")]
+    credit_limit: Currency,
+    #[serde(default, deserialize_with = "from_optional_float_string")]
+    max_withdrawal: Option<Currency>,
+    #[serde(default)]
+    max_withdrawal_count: Option<u64>,
+    #[serde(default, deserialize_with = "from_optional_float_string")]
+    max_withdrawal_total: Option<Currency>,
+}
+
+/*
+ * Like the input, a file with anything wrong in it is refused as a whole: a
+ * client that's in it twice, or a limit below 0.
+ * */
+pub fn read(path: &str) -> Result<BTreeMap<u16, ClientConfig>, Csv> {
+    let rows = csv::Reader::from_path(path)
+        .map_err(|e| Csv::FileReadError(format!("Error reading clients: {:?}", e)))?
+        .deserialize::<Row>()
+        .map(|row| row.map_err(|e| format!("{:?}", e)))
+        .collect::<Vec<Result<Row, String>>>();
+
+    let mut clients = BTreeMap::new();
+    let mut errors = vec![];
+    for row in rows {
+        match row {
+            Ok(row) if row.credit_limit.0 < 0 => {
+                errors.push(format!("Client {} has a credit limit below 0", row.client))
+            }
+            Ok(row)
+                if [row.max_withdrawal, row.max_withdrawal_total]
+                    .iter()
+                    .flatten()
+                    .any(|max| max.0 < 0) =>
+            {
+                errors.push(format!(
+                    "Client {} has a withdrawal limit below 0",
+                    row.client
+                ))
+            }
+            Ok(row) => {
+                let config = ClientConfig {
+                    credit_limit: row.credit_limit,
+                    withdrawal_limits: WithdrawalLimits {
+                        max_amount: row.max_withdrawal,
+                        max_count: row.max_withdrawal_count,
+                        max_total: row.max_withdrawal_total,
+                    },
+                };
+                if clients.insert(row.client, config).is_some() {
+                    errors.push(format!("Client {} is in there more than once", row.client));
+                }
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Csv::ParseError(errors));
+    }
+    Ok(clients)
+}
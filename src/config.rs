@@ -0,0 +1,321 @@
+use crate::clients;
+use crate::currency;
+use crate::ledger;
+use crate::limits;
+use crate::parallel_reader;
+use crate::reorder;
+use crate::rules;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/*
+ * Everything the command line can ask for, out of its flags. Flags that don't
+ * make sense together are refused here, before anything is read, with the
+ * message to show for it.
+ * */
+
+/*
+ * How the input gets read. They all end up with the same transactions, in
+ * the same order.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Input {
+    Csv,
+    Mapped,
+    Parallel(usize),
+}
+
+/*
+ * Where deposits are kept: in memory, mostly on disk with a bounded number of
+ * them in memory, or in memory and in a snapshot in between runs. A snapshot
+ * can come with a write-ahead log, for runs that get killed halfway.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Store {
+    Memory,
+    Disk(String, usize),
+    Snapshot(String, Option<String>),
+}
+
+/*
+ * Anything that's kept track of next to the accounts: the events that led to
+ * them, a double-entry journal of every transaction, or a check of the ledger's
+ * invariants after each one. Only with a single in-memory ledger.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Record {
+    Events(String),
+    Journal(String),
+    Invariants,
+}
+
+/* How to go about a regular run over an input file */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Run {
+    pub input: Input,
+    pub shards: Option<usize>,
+    pub store: Store,
+    pub reorder: Option<reorder::Config>,
+    pub record: Option<Record>,
+    pub ledger: ledger::Config,
+    /* Where flagged transactions go, stderr if it's not given */
+    pub review: Option<String>,
+}
+
+/*
+ * Flags are always of the form `--name` or `--name=value`, so they can be
+ * split off from the positional arguments without knowing about them upfront.
+ * */
+pub fn flag<'a>(flags: &[&'a String], name: &str) -> Option<&'a str> {
+    flags.iter().find_map(|f| {
+        let f = f.trim_start_matches("--");
+        if f == name {
+            Some("")
+        } else {
+            f.strip_prefix(name).and_then(|rest| rest.strip_prefix('='))
+        }
+    })
+}
+
+/*
+ * A plain number is a number of transactions, anything with a unit is time:
+ * `500`, `90s`, `30m`, `12h` or `7d`.
+ * */
+pub fn window(window: &str) -> Result<ledger::DisputeWindow, String> {
+    let invalid = |e| format!("Invalid window {}: {:?}", window, e);
+    let (number, seconds) = match window.char_indices().last() {
+        Some((at, 's')) => (&window[..at], 1),
+        Some((at, 'm')) => (&window[..at], 60),
+        Some((at, 'h')) => (&window[..at], 60 * 60),
+        Some((at, 'd')) => (&window[..at], 24 * 60 * 60),
+        _ => {
+            return window
+                .parse::<u64>()
+                .map(ledger::DisputeWindow::Transactions)
+                .map_err(invalid)
+        }
+    };
+    let number = number.parse::<u64>().map_err(invalid)?;
+    number
+        .checked_mul(seconds)
+        .map(|seconds| ledger::DisputeWindow::Time(Duration::from_secs(seconds)))
+        .ok_or_else(|| format!("Invalid window {}: too long", window))
+}
+
+/*
+ * Reordering by time with a window like `5s`, or by tx id with a number.
+ * */
+fn reorder(flags: &[&String]) -> Result<Option<reorder::Config>, String> {
+    let (order, window) = match flag(flags, "reorder-window").map(window).transpose()? {
+        None => return Ok(None),
+        Some(ledger::DisputeWindow::Time(time)) => {
+            (reorder::Order::Timestamp, time.as_millis() as u64)
+        }
+        Some(ledger::DisputeWindow::Transactions(transactions)) => {
+            (reorder::Order::Sequence, transactions)
+        }
+    };
+    let capacity = flag(flags, "reorder-capacity")
+        .map_or(Ok(100_000), str::parse::<usize>)
+        .map_err(|e| format!("Invalid reorder capacity: {:?}", e))?;
+    let late = match flag(flags, "late") {
+        None | Some("reject") => reorder::LatePolicy::Reject,
+        Some("append") => reorder::LatePolicy::Append,
+        Some("recompute") => reorder::LatePolicy::Recompute,
+        Some(other) => return Err(format!("Unknown late policy: {}", other)),
+    };
+    Ok(Some(reorder::Config {
+        order,
+        window,
+        capacity,
+        late,
+    }))
+}
+
+/* A limit that's off unless it's given, and can't be below 0 */
+fn limit<T: std::str::FromStr + PartialOrd + Default>(
+    flags: &[&String],
+    name: &str,
+) -> Result<Option<T>, String> {
+    flag(flags, name)
+        .map(|value| match value.parse::<T>() {
+            Ok(value) if value >= T::default() => Ok(value),
+            _ => Err(format!("Invalid --{}: {}", name, value)),
+        })
+        .transpose()
+}
+
+pub fn ledger(flags: &[&String]) -> Result<ledger::Config, String> {
+    let zero_amount = match flag(flags, "zero-amounts") {
+        None | Some("warn") => ledger::ZeroAmountPolicy::Warn,
+        Some("reject") => ledger::ZeroAmountPolicy::Reject,
+        Some(other) => return Err(format!("Unknown zero amount policy: {}", other)),
+    };
+    let dispute_window = match flag(flags, "dispute-window") {
+        None => None,
+        Some(dispute_window) => Some(window(dispute_window)?),
+    };
+    let out_of_order = match flag(flags, "out-of-order") {
+        None | Some("allow") => ledger::OutOfOrderPolicy::Allow,
+        Some("reject") => ledger::OutOfOrderPolicy::Reject,
+        Some(other) => return Err(format!("Unknown out of order policy: {}", other)),
+    };
+    let clients = match flag(flags, "clients") {
+        None => BTreeMap::new(),
+        Some("") => return Err("Please provide a path with --clients=<path>".to_string()),
+        Some(path) => clients::read(path).map_err(|e| format!("{:?}", e))?,
+    };
+    let overdraft = match flag(flags, "overdraft") {
+        None | Some("allow") => ledger::OverdraftPolicy::Allow,
+        Some("reject") => ledger::OverdraftPolicy::Reject,
+        Some("receivable") => ledger::OverdraftPolicy::Receivable,
+        Some(other) => return Err(format!("Unknown overdraft policy: {}", other)),
+    };
+    let withdrawal_limits = limits::WithdrawalLimits {
+        max_amount: limit::<f64>(flags, "max-withdrawal")?.map(currency::from_float),
+        max_count: limit(flags, "max-withdrawal-count")?,
+        max_total: limit::<f64>(flags, "max-withdrawal-total")?.map(currency::from_float),
+    };
+    let withdrawal_window = match flag(flags, "withdrawal-window") {
+        None => None,
+        Some(withdrawal_window) => Some(window(withdrawal_window)?),
+    };
+    let rules = rules::Config {
+        large_deposit: limit::<f64>(flags, "flag-deposits-over")?.map(currency::from_float),
+        rapid_withdrawal: flag(flags, "flag-withdrawals-within")
+            .map(window)
+            .transpose()?,
+        max_disputes: limit(flags, "flag-disputes-after")?,
+    };
+    /* Events only move available and held, what's owed would get lost */
+    if overdraft == ledger::OverdraftPolicy::Receivable && flag(flags, "events").is_some() {
+        return Err("--events can't be combined with --overdraft=receivable".to_string());
+    }
+    Ok(ledger::Config {
+        zero_amount,
+        dispute_window,
+        out_of_order,
+        overdraft,
+        clients,
+        withdrawal_limits,
+        withdrawal_window,
+        rules,
+    })
+}
+
+pub fn run(flags: &[&String], ledger: ledger::Config) -> Result<Run, String> {
+    let input = match (flag(flags, "threads"), flag(flags, "mmap")) {
+        (Some(""), _) => Input::Parallel(parallel_reader::default_threads()),
+        (Some(threads), _) => threads
+            .parse::<usize>()
+            .map(Input::Parallel)
+            .map_err(|e| format!("Invalid number of threads: {:?}", e))?,
+        (None, Some(_)) => Input::Mapped,
+        (None, None) => Input::Csv,
+    };
+    let shards = flag(flags, "shards")
+        .map(str::parse::<usize>)
+        .transpose()
+        .map_err(|e| format!("Invalid number of shards: {:?}", e))?;
+    let store = match (flag(flags, "spill-to"), shards) {
+        (None, _) => match flag(flags, "snapshot") {
+            None => Store::Memory,
+            Some("") => {
+                return Err(
+                    "Please provide a path for the snapshot with --snapshot=<path>".to_string(),
+                )
+            }
+            Some(path) => Store::Snapshot(path.to_string(), flag(flags, "wal").map(str::to_string)),
+        },
+        (Some(""), _) => {
+            return Err(
+                "Please provide a path to spill deposits to with --spill-to=<path>".to_string(),
+            )
+        }
+        (Some(_), Some(_)) => return Err("--spill-to can't be combined with --shards".to_string()),
+        (Some(path), None) => {
+            let megabytes = flag(flags, "memory-budget")
+                .map_or(Ok(64), str::parse::<usize>)
+                .map_err(|e| format!("Invalid memory budget: {:?}", e))?;
+            Store::Disk(path.to_string(), megabytes * 1024 * 1024)
+        }
+    };
+    let reorder = reorder(flags)?;
+    if reorder.is_some() && shards.is_some() {
+        return Err("--reorder-window can't be combined with --shards".to_string());
+    }
+    /*
+     * Replaying a client moves the ledger along, so whatever's counted in
+     * transactions would be counted from where the replay got to, not from
+     * where each of them was the first time around. Deposits that expired in
+     * between would come back, too.
+     * */
+    let recompute = reorder.is_some_and(|reorder| reorder.late == reorder::LatePolicy::Recompute);
+    if recompute && (ledger.dispute_window.is_some() || ledger.withdrawal_window.is_some()) {
+        return Err(
+            "--late=recompute can't be combined with --dispute-window or --withdrawal-window"
+                .to_string(),
+        );
+    }
+    match (&store, flag(flags, "wal")) {
+        (Store::Snapshot(_, Some(log)), _) if log.is_empty() => {
+            return Err("Please provide a path for the log with --wal=<path>".to_string())
+        }
+        (Store::Snapshot(..), _) | (_, None) => (),
+        (_, Some(_)) => return Err("--wal needs a --snapshot to go with it".to_string()),
+    }
+    if let Store::Snapshot(..) = store {
+        if shards.is_some() || reorder.is_some() || flag(flags, "spill-to").is_some() {
+            return Err("--snapshot only works with a single in-memory ledger".to_string());
+        }
+        if input != Input::Csv {
+            return Err(
+                "--snapshot reads the file on its own, without --mmap or --threads".to_string(),
+            );
+        }
+    }
+    let single = store == Store::Memory && shards.is_none() && reorder.is_none();
+    let record = match (flag(flags, "events"), flag(flags, "journal")) {
+        (Some(""), _) | (_, Some("")) => {
+            return Err(
+                "Please provide a path with --events=<path> or --journal=<path>".to_string(),
+            )
+        }
+        (Some(_), Some(_)) => return Err("--events can't be combined with --journal".to_string()),
+        (None, None) => None,
+        _ if !single => {
+            return Err(
+                "--events and --journal only work with a single in-memory ledger".to_string(),
+            )
+        }
+        (Some(log), None) => Some(Record::Events(log.to_string())),
+        (None, Some(path)) => Some(Record::Journal(path.to_string())),
+    };
+    let record = match (record, flag(flags, "check-invariants")) {
+        (record, None) => record,
+        (Some(_), Some(_)) => {
+            return Err(
+                "--check-invariants can't be combined with --events or --journal".to_string(),
+            )
+        }
+        (None, Some(_)) if !single => {
+            return Err("--check-invariants only works with a single in-memory ledger".to_string())
+        }
+        (None, Some(_)) => Some(Record::Invariants),
+    };
+    let review = match flag(flags, "review") {
+        Some("") => {
+            return Err("Please provide a path for the review with --review=<path>".to_string())
+        }
+        review => review.map(str::to_string),
+    };
+    Ok(Run {
+        input,
+        shards,
+        store,
+        reorder,
+        record,
+        ledger,
+        review,
+    })
+}
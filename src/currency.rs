@@ -20,7 +20,7 @@ const PRECISION: f64 = 10000.0;
  *    can be upped to 128 bits if need be.
  * */
 
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
 pub struct Currency(pub i64);
 
 fn to_f64(x: Currency) -> f64 {
@@ -30,8 +30,12 @@ fn to_f64(x: Currency) -> f64 {
 /* -------------------------- */
 /* ---- Money Operations ---- */
 /* -------------------------- */
+/*
+ * Rounded rather than truncated, so whatever we serialize comes back as the
+ * exact same Currency. 0.0003 * 10000.0 is 2.9999999999999996 after all.
+ * */
 pub fn from_float(x: f64) -> Currency {
-    Currency((x * PRECISION) as i64)
+    Currency((x * PRECISION).round() as i64)
 }
 
 pub fn add(x: Currency, y: Currency) -> Currency {
@@ -61,6 +65,18 @@ pub fn safe_subtract_silent(x: Currency, y: Currency) -> Currency {
 /* ------------------------- */
 /* Serializer / Deserializer */
 /* ------------------------- */
+/*
+ * Rust reads "inf" and "NaN" as floats too, but they aren't amounts, and
+ * would end up as i64::MAX or 0.
+ * */
+pub fn parse(s: &str) -> Result<Currency, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(from_float(x)),
+        Ok(x) => Err(format!("{} is not an amount", x)),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
 /*
  * The serializer parses an empty string as 0. While technically incorrect,
  * this is the best way to deal with it in the context of this toy
@@ -71,10 +87,18 @@ where
 {
     match Deserialize::deserialize(deserializer)? {
         "" => Ok(Currency(0)),
-        s => s
-            .parse::<f64>()
-            .map_err(D::Error::custom)
-            .map(|x| Currency((x * PRECISION) as i64)),
+        s => parse(s).map_err(D::Error::custom),
+    }
+}
+
+/* Same, but an empty string is nothing at all */
+pub fn from_optional_float_string<'de, D>(deserializer: D) -> Result<Option<Currency>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Deserialize::deserialize(deserializer)? {
+        "" => Ok(None),
+        s => parse(s).map_err(D::Error::custom).map(Some),
     }
 }
 
@@ -0,0 +1,61 @@
+use crate::currency::Currency;
+use crate::tx_table::TxTable;
+use std::io;
+
+/*
+ * Where a deposit is at. Only deposits can be disputed, and a dispute always
+ * ends in either a resolve (back to `Deposited`) or a chargeback, after which
+ * there's nothing left to do with it.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DepositState {
+    Deposited,
+    Disputed,
+    ChargedBack,
+}
+
+/*
+ * Everything we need to remember about a deposit to be able to dispute it
+ * later on: who it belongs to, how much it was, and where it's at. The tx id
+ * itself is the key it's stored under.
+ *
+ * Memory cost: 16 bytes per tracked deposit (8 for the amount, 2 for the client,
+ * 1 for the state, padded to 16). `Option<Deposit>` fits in those same 16
+ * bytes, as the state has room to spare for the `None`. Stored in a `TxTable`,
+ * every page of 4096 of them takes 64KB, so with tx ids handed out in order
+ * that's 16 bytes per deposit, on the dot. A full `Transaction` is 24.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Deposit {
+    pub amount: Currency,
+    pub client: u16,
+    pub state: DepositState,
+}
+
+pub const BYTES_PER_DEPOSIT: usize = 16;
+
+/*
+ * Wherever the deposits are kept. The ledger only ever looks a deposit up,
+ * stores it, or forgets about it, so that's all a store needs to do. Anything
+ * that isn't kept in memory can fail, hence the `io::Result`s.
+ * */
+pub trait DepositStore {
+    fn get(&mut self, tx: u32) -> io::Result<Option<Deposit>>;
+    fn insert(&mut self, tx: u32, deposit: Deposit) -> io::Result<()>;
+    fn remove(&mut self, tx: u32) -> io::Result<Option<Deposit>>;
+}
+
+impl DepositStore for TxTable<Deposit> {
+    fn get(&mut self, tx: u32) -> io::Result<Option<Deposit>> {
+        Ok(TxTable::get(self, tx).copied())
+    }
+
+    fn insert(&mut self, tx: u32, deposit: Deposit) -> io::Result<()> {
+        TxTable::insert(self, tx, deposit);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: u32) -> io::Result<Option<Deposit>> {
+        Ok(TxTable::remove(self, tx))
+    }
+}
@@ -0,0 +1,213 @@
+use crate::currency::Currency;
+use crate::deposit::{Deposit, DepositState, DepositStore};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/*
+ * A deposit store that only keeps a bounded number of deposits in memory. The
+ * ones that were touched most recently stay in memory, the rest is written to
+ * a file on disk, and read back whenever a dispute comes in for them.
+ *
+ * The file is addressed directly by tx id: the deposit with tx id N lives at
+ * byte N * RECORD_SIZE. There's no index to keep in memory that way, whatever
+ * the number of deposits. Tx ids that were never spilled are holes in the file,
+ * which don't take up any space on disk, and read back as zeroes, which is an
+ * empty record.
+ *
+ * Deposits are evicted in the order they were last touched in. Deposits are
+ * mostly disputed shortly after they're made, if at all, so the ones that
+ * haven't been looked at in the longest are the least likely to be needed
+ * again. Every deposit in memory has exactly one place in that order, which
+ * moves to the back whenever it's touched.
+ * */
+
+const RECORD_SIZE: u64 = 16;
+
+/*
+ * What a deposit in memory costs, roughly: the deposit itself, the tx id and
+ * bookkeeping next to it, plus the hash map's own overhead, and its entry in
+ * the eviction queue.
+ * */
+pub const BYTES_PER_HOT_DEPOSIT: usize = 64;
+
+struct Hot {
+    deposit: Deposit,
+    generation: u64,
+    dirty: bool,
+}
+
+pub struct DiskStore {
+    path: PathBuf,
+    file: File,
+    hot: HashMap<u32, Hot>,
+    /* Deposits in memory by when they were last touched, oldest first */
+    order: BTreeMap<u64, u32>,
+    generation: u64,
+    capacity: usize,
+}
+
+fn encode(deposit: Option<&Deposit>) -> [u8; RECORD_SIZE as usize] {
+    let mut record = [0; RECORD_SIZE as usize];
+    if let Some(deposit) = deposit {
+        record[0..8].copy_from_slice(&deposit.amount.0.to_le_bytes());
+        record[8..10].copy_from_slice(&deposit.client.to_le_bytes());
+        record[10] = match deposit.state {
+            DepositState::Deposited => 1,
+            DepositState::Disputed => 2,
+            DepositState::ChargedBack => 3,
+        };
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE as usize]) -> io::Result<Option<Deposit>> {
+    let state = match record[10] {
+        0 => return Ok(None),
+        1 => DepositState::Deposited,
+        2 => DepositState::Disputed,
+        3 => DepositState::ChargedBack,
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown deposit state {}", other),
+            ))
+        }
+    };
+    let mut amount = [0; 8];
+    amount.copy_from_slice(&record[0..8]);
+    Ok(Some(Deposit {
+        amount: Currency(i64::from_le_bytes(amount)),
+        client: u16::from_le_bytes([record[8], record[9]]),
+        state,
+    }))
+}
+
+impl DiskStore {
+    /*
+     * Starts with an empty file at `path`, whatever was there before. The file
+     * is removed again once the store is dropped.
+     * */
+    pub fn create(path: &str, memory_budget: usize) -> io::Result<DiskStore> {
+        Ok(DiskStore {
+            path: PathBuf::from(path),
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+            hot: HashMap::new(),
+            order: BTreeMap::new(),
+            generation: 0,
+            capacity: (memory_budget / BYTES_PER_HOT_DEPOSIT).max(1),
+        })
+    }
+
+    pub fn in_memory(&self) -> usize {
+        self.hot.len()
+    }
+
+    pub fn holds(&self, tx: u32) -> bool {
+        self.hot.contains_key(&tx)
+    }
+
+    fn read(&mut self, tx: u32) -> io::Result<Option<Deposit>> {
+        let offset = tx as u64 * RECORD_SIZE;
+        if offset + RECORD_SIZE > self.file.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut record = [0; RECORD_SIZE as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut record)?;
+        decode(&record)
+    }
+
+    fn write(&mut self, tx: u32, deposit: Option<&Deposit>) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(tx as u64 * RECORD_SIZE))?;
+        self.file.write_all(&encode(deposit))
+    }
+
+    /* Moves the deposit to the back of the order */
+    fn touch(&mut self, tx: u32) {
+        if let Some(hot) = self.hot.get_mut(&tx) {
+            self.generation += 1;
+            self.order.remove(&hot.generation);
+            self.order.insert(self.generation, tx);
+            hot.generation = self.generation;
+        }
+    }
+
+    fn keep(&mut self, tx: u32, deposit: Deposit, dirty: bool) -> io::Result<()> {
+        let hot = self.hot.entry(tx).or_insert(Hot {
+            deposit,
+            generation: 0,
+            dirty,
+        });
+        hot.deposit = deposit;
+        hot.dirty |= dirty;
+        self.touch(tx);
+        debug_assert_eq!(self.order.len(), self.hot.len());
+        self.evict()
+    }
+
+    fn evict(&mut self) -> io::Result<()> {
+        while self.hot.len() > self.capacity {
+            let tx = match self.order.pop_first() {
+                Some((_, tx)) => tx,
+                None => return Ok(()),
+            };
+            if let Some(hot) = self.hot.remove(&tx) {
+                if hot.dirty {
+                    self.write(tx, Some(&hot.deposit))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DepositStore for DiskStore {
+    fn get(&mut self, tx: u32) -> io::Result<Option<Deposit>> {
+        if let Some(deposit) = self.hot.get(&tx).map(|hot| hot.deposit) {
+            self.touch(tx);
+            return Ok(Some(deposit));
+        }
+        match self.read(tx)? {
+            Some(deposit) => {
+                self.keep(tx, deposit, false)?;
+                Ok(Some(deposit))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, tx: u32, deposit: Deposit) -> io::Result<()> {
+        self.keep(tx, deposit, true)
+    }
+
+    /*
+     * Whatever's on disk has to go as well, or it would come back on the next
+     * lookup.
+     * */
+    fn remove(&mut self, tx: u32) -> io::Result<Option<Deposit>> {
+        let removed = match self.hot.remove(&tx) {
+            Some(hot) => {
+                self.order.remove(&hot.generation);
+                Some(hot.deposit)
+            }
+            None => self.read(tx)?,
+        };
+        if tx as u64 * RECORD_SIZE < self.file.metadata()?.len() {
+            self.write(tx, None)?;
+        }
+        Ok(removed)
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::deposit::DepositState;
+use crate::limits::Limit;
 use crate::transaction;
 
 #[derive(Debug, Eq, PartialEq)]
@@ -6,17 +8,49 @@ pub enum Csv {
     ParseError(Vec<String>),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum UnsettledReason {
     InsufficientFunds,
     TransactionOrAccountNotFound,
+    InvalidState(DepositState),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum TransactionError {
     AccountlessAction(transaction::Transaction),
     UnsettledWithdrawal(transaction::Transaction, UnsettledReason),
     UnsettledDispute(transaction::Transaction, UnsettledReason),
     UnsettledResolve(transaction::Transaction, UnsettledReason),
     UnsettledChargeback(transaction::Transaction, UnsettledReason),
+    NegativeAmount(transaction::Transaction),
+    ZeroAmount(transaction::Transaction),
+    DisputeWindowExpired(transaction::Transaction),
+    /* A deposit with the tx id of one that came before it */
+    DuplicateTransaction(transaction::Transaction),
+    OutOfOrder(transaction::Transaction),
+    Late(transaction::Transaction),
+    StorageFailure(transaction::Transaction, String),
+    /* Went through, but the journal entry for it didn't balance */
+    Unbalanced(transaction::Transaction),
+    /* A withdrawal that would have gone over one of the withdrawal limits */
+    WithdrawalLimit(transaction::Transaction, Limit),
+    /* Refused by a rule, with the rule's name and its reason, see `Rule` */
+    Denied(transaction::Transaction, String, String),
+    /* Went through, but a rule wants someone to have a look at it */
+    Flagged(transaction::Transaction, String, String),
+}
+
+/*
+ * Problems found when validating an input file without applying it. Every
+ * variant carries the line of the input it was found on.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub enum Validation {
+    MalformedRow(u64, String),
+    NegativeAmount(u64, transaction::Transaction),
+    ZeroAmount(u64, transaction::Transaction),
+    UnexpectedAmount(u64, transaction::Transaction),
+    DuplicateTransaction(u64, transaction::Transaction),
+    UnknownTransaction(u64, transaction::Transaction),
+    OutOfOrder(u64, transaction::Transaction),
 }
@@ -0,0 +1,287 @@
+use crate::account::{new_from_client_id, Account};
+use crate::clients::ClientConfig;
+use crate::currency::{from_float_string, to_float_string, Currency};
+use crate::deposit::DepositStore;
+use crate::error::{Csv, TransactionError};
+use crate::ledger::Ledger;
+use crate::transaction::{Transaction, TransactionType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+
+/*
+ * What happened to an account, one step at a time. A transaction that went
+ * through turns into one or two of these, one that didn't into none. Nothing
+ * else is needed to get to the accounts: start with nothing, and apply them in
+ * order. The amounts are what the account actually moved by, so a dispute holds
+ * whatever the deposit was for, not the 0 on the dispute row.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Event {
+    AccountOpened {
+        client: u16,
+    },
+    FundsDeposited {
+        client: u16,
+        tx: u32,
+        amount: Currency,
+    },
+    FundsWithdrawn {
+        client: u16,
+        tx: u32,
+        amount: Currency,
+    },
+    FundsHeld {
+        client: u16,
+        tx: u32,
+        amount: Currency,
+    },
+    FundsReleased {
+        client: u16,
+        tx: u32,
+        amount: Currency,
+    },
+    ChargedBack {
+        client: u16,
+        tx: u32,
+        amount: Currency,
+    },
+    AccountLocked {
+        client: u16,
+    },
+}
+
+/*
+ * Applies the transaction, and tells what it did to the account by comparing
+ * it before and after. That keeps the ledger itself out of it.
+ * */
+pub fn apply<S: DepositStore>(
+    ledger: &mut Ledger<S>,
+    transaction: &Transaction,
+) -> (Result<(), TransactionError>, Vec<Event>) {
+    let client = transaction.client;
+    let tx = transaction.tx;
+    let before = ledger.account(client);
+    let result = ledger.apply(transaction);
+    let after = match (&result, ledger.account(client)) {
+        (Ok(()), Some(after)) => after,
+        _ => return (result, vec![]),
+    };
+    let was = before.unwrap_or_else(|| new_from_client_id(client));
+    let moved = |from: Currency, to: Currency| Currency((to.0 - from.0).abs());
+
+    let mut events = vec![];
+    if before.is_none() {
+        events.push(Event::AccountOpened { client });
+    }
+    events.push(match transaction.tx_type {
+        TransactionType::Deposit => Event::FundsDeposited {
+            client,
+            tx,
+            amount: moved(was.total(), after.total()),
+        },
+        TransactionType::Withdrawal => Event::FundsWithdrawn {
+            client,
+            tx,
+            amount: moved(was.total(), after.total()),
+        },
+        TransactionType::Dispute => Event::FundsHeld {
+            client,
+            tx,
+            amount: moved(was.held(), after.held()),
+        },
+        TransactionType::Resolve => Event::FundsReleased {
+            client,
+            tx,
+            amount: moved(was.held(), after.held()),
+        },
+        TransactionType::Chargeback => Event::ChargedBack {
+            client,
+            tx,
+            amount: moved(was.total(), after.total()),
+        },
+    });
+    if after.locked() && !was.locked() {
+        events.push(Event::AccountLocked { client });
+    }
+    (result, events)
+}
+
+/*
+ * The accounts the events lead to, sorted by client. Events for an account
+ * that was never opened, or that take out more than there is, mean the log
+ * isn't one we wrote. What there is includes the credit lines in `clients`,
+ * which have to be the ones the log was written with.
+ * */
+pub fn rebuild(
+    events: &[Event],
+    clients: &BTreeMap<u16, ClientConfig>,
+) -> Result<Vec<Account>, String> {
+    let mut accounts: BTreeMap<u16, Account> = BTreeMap::new();
+    for event in events {
+        if let Event::AccountOpened { client } = event {
+            if accounts
+                .insert(*client, new_from_client_id(*client))
+                .is_some()
+            {
+                return Err(format!("{:?} for an account that's already open", event));
+            }
+            continue;
+        }
+        let account = accounts
+            .get_mut(&client(event))
+            .ok_or_else(|| format!("{:?} before the account was opened", event))?;
+        let too_much = |_| format!("{:?} takes out more than there is", event);
+        let credit_limit = clients
+            .get(&client(event))
+            .copied()
+            .unwrap_or_default()
+            .credit_limit;
+        match *event {
+            Event::AccountOpened { .. } => unreachable!("handled above"),
+            Event::FundsDeposited { amount, .. } => account.credit(amount),
+            Event::FundsWithdrawn { amount, .. } => {
+                account.debit(amount, credit_limit).map_err(too_much)?
+            }
+            /* A dispute can take available below 0, same as in the ledger */
+            Event::FundsHeld { amount, .. } => account.hold(amount),
+            Event::FundsReleased { amount, .. } => account.release(amount).map_err(too_much)?,
+            Event::ChargedBack { amount, .. } => {
+                account.reverse(amount, credit_limit).map_err(too_much)?
+            }
+            Event::AccountLocked { .. } => account.lock(),
+        }
+    }
+    Ok(accounts.into_values().collect())
+}
+
+fn client(event: &Event) -> u16 {
+    match *event {
+        Event::AccountOpened { client }
+        | Event::FundsDeposited { client, .. }
+        | Event::FundsWithdrawn { client, .. }
+        | Event::FundsHeld { client, .. }
+        | Event::FundsReleased { client, .. }
+        | Event::ChargedBack { client, .. }
+        | Event::AccountLocked { client } => client,
+    }
+}
+
+/*
+ * Same story as with `Transaction`: enums with data don't go in and out of a
+ * CSV, so the log has a flat row with a kind, and the columns an event doesn't
+ * use left empty or 0.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+enum Kind {
+    AccountOpened,
+    FundsDeposited,
+    FundsWithdrawn,
+    FundsHeld,
+    FundsReleased,
+    ChargedBack,
+    AccountLocked,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Row {
+    event: Kind,
+    client: u16,
+    tx: Option<u32>,
+    #[serde(
+        serialize_with = "to_float_string",
+        deserialize_with = "from_float_string"
+    )]
+    amount: Currency,
+}
+
+fn to_row(event: &Event) -> Row {
+    let client = client(event);
+    let (event, tx, amount) = match *event {
+        Event::AccountOpened { .. } => (Kind::AccountOpened, None, Currency(0)),
+        Event::FundsDeposited { tx, amount, .. } => (Kind::FundsDeposited, Some(tx), amount),
+        Event::FundsWithdrawn { tx, amount, .. } => (Kind::FundsWithdrawn, Some(tx), amount),
+        Event::FundsHeld { tx, amount, .. } => (Kind::FundsHeld, Some(tx), amount),
+        Event::FundsReleased { tx, amount, .. } => (Kind::FundsReleased, Some(tx), amount),
+        Event::ChargedBack { tx, amount, .. } => (Kind::ChargedBack, Some(tx), amount),
+        Event::AccountLocked { .. } => (Kind::AccountLocked, None, Currency(0)),
+    };
+    Row {
+        event,
+        client,
+        tx,
+        amount,
+    }
+}
+
+fn from_row(row: &Row) -> Result<Event, String> {
+    let client = row.client;
+    let amount = row.amount;
+    let tx = || row.tx.ok_or_else(|| format!("{:?} without a tx id", row));
+    Ok(match row.event {
+        Kind::AccountOpened => Event::AccountOpened { client },
+        Kind::FundsDeposited => Event::FundsDeposited {
+            client,
+            tx: tx()?,
+            amount,
+        },
+        Kind::FundsWithdrawn => Event::FundsWithdrawn {
+            client,
+            tx: tx()?,
+            amount,
+        },
+        Kind::FundsHeld => Event::FundsHeld {
+            client,
+            tx: tx()?,
+            amount,
+        },
+        Kind::FundsReleased => Event::FundsReleased {
+            client,
+            tx: tx()?,
+            amount,
+        },
+        Kind::ChargedBack => Event::ChargedBack {
+            client,
+            tx: tx()?,
+            amount,
+        },
+        Kind::AccountLocked => Event::AccountLocked { client },
+    })
+}
+
+/*
+ * Empties the log. A run starts with this, the log is only ever about a single
+ * run, so it always rebuilds to the accounts that run ended up with.
+ * */
+pub fn truncate(path: &str) -> io::Result<()> {
+    File::create(path).map(|_| ())
+}
+
+/*
+ * Adds the events to the end of the log, and starts it with a header if it's
+ * new.
+ * */
+pub fn append(path: &str, events: &[Event]) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let empty = file.metadata()?.len() == 0;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(empty)
+        .from_writer(file);
+    for event in events {
+        writer.serialize(to_row(event))?;
+    }
+    writer.flush()
+}
+
+pub fn read(path: &str) -> Result<Vec<Event>, Csv> {
+    csv::Reader::from_path(path)
+        .map_err(|e| Csv::FileReadError(format!("Error reading events: {:?}", e)))?
+        .deserialize::<Row>()
+        .map(|row| {
+            row.map_err(|e| format!("{:?}", e))
+                .and_then(|row| from_row(&row))
+        })
+        .collect::<Result<Vec<Event>, String>>()
+        .map_err(|e| Csv::ParseError(vec![e]))
+}
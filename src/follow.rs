@@ -0,0 +1,198 @@
+use crate::error::{Csv, TransactionError};
+use crate::ledger::{Config, Ledger};
+use crate::snapshot::{self, Snapshot};
+use crate::transaction::Transaction;
+use crate::writer;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+/*
+ * Like `tail -f`, but for transactions. The ledger stays in memory, and every
+ * poll picks up whatever got appended to the file since the last one.
+ *
+ * Only complete lines (ending in a newline) are processed. Whatever comes after
+ * the last newline is most likely still being written, so we keep it around and
+ * try again on the next poll. `offset` always points at the start of the first
+ * line that hasn't been processed yet, which makes it safe to resume from.
+ * */
+pub struct Follower {
+    file: File,
+    offset: u64,
+    pending: Vec<u8>,
+    headers: Option<csv::StringRecord>,
+    ledger: Ledger,
+}
+
+/*
+ * The outcome of a single poll. Malformed rows are skipped, as there's no way
+ * to discard the whole file once we've started applying it. Warnings are for
+ * the rows that did go through, see `Ledger::take_warnings`.
+ * */
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub rows: usize,
+    pub errors: Vec<TransactionError>,
+    pub warnings: Vec<TransactionError>,
+    pub malformed: Vec<String>,
+}
+
+fn read_error(e: io::Error) -> Csv {
+    Csv::FileReadError(format!("Error reading file: {:?}", e))
+}
+
+/*
+ * The byte offset and the ledger that go with it live together in a single
+ * file next to the output, so they can only ever be replaced together. The
+ * first line holds the offset, the rest is a snapshot of the whole ledger,
+ * deposits and disputes included, so a follower that's restarted carries on
+ * like it never stopped.
+ * */
+fn state_path(output: &str) -> String {
+    format!("{}.state", output)
+}
+
+/*
+ * Write to a temporary file first and move it in place after, so whoever reads
+ * the output never sees half a snapshot.
+ * */
+pub fn write_atomically(path: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+pub fn read_headers<R: Read + Seek>(file: &mut R) -> Result<Option<csv::StringRecord>, Csv> {
+    file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+    let mut line = vec![];
+    BufReader::new(file)
+        .read_until(b'\n', &mut line)
+        .map_err(read_error)?;
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(line.as_slice())
+        .records()
+        .next()
+        .transpose()
+        .map_err(|e| Csv::ParseError(vec![format!("{:?}", e)]))
+}
+
+impl Follower {
+    pub fn new(path: &str, config: Config) -> Result<Follower, Csv> {
+        Ok(Follower {
+            file: File::open(path).map_err(read_error)?,
+            offset: 0,
+            pending: vec![],
+            headers: None,
+            ledger: Ledger::new(config),
+        })
+    }
+
+    /*
+     * Picks up where a previous run left off, if `output` was written by one.
+     * */
+    pub fn resume(path: &str, output: &str, config: Config) -> Result<Follower, Csv> {
+        let mut follower = Follower::new(path, config)?;
+        let state = match fs::read(state_path(output)) {
+            Ok(state) => state,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(follower),
+            Err(e) => return Err(read_error(e)),
+        };
+        let first_newline = state
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(state.len());
+        let offset = String::from_utf8_lossy(&state[..first_newline])
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| Csv::FileReadError(format!("Error reading offset: {:?}", e)))?;
+
+        let snapshot = snapshot::decode(state.get(first_newline + 1..).unwrap_or(&[]))
+            .map_err(|e| Csv::FileReadError(format!("Error reading ledger: {:?}", e)))?;
+        follower.ledger = Ledger::from_state(follower.ledger.config().clone(), snapshot.state);
+
+        if offset > 0 {
+            follower.headers = read_headers(&mut follower.file)?;
+        }
+        follower.offset = offset;
+        Ok(follower)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn poll(&mut self) -> Result<Batch, Csv> {
+        let read_from = self.offset + self.pending.len() as u64;
+        if self.file.metadata().map_err(read_error)?.len() < read_from {
+            return Err(Csv::FileReadError(
+                "File shrank while following it".to_string(),
+            ));
+        }
+        self.file
+            .seek(SeekFrom::Start(read_from))
+            .map_err(read_error)?;
+        self.file
+            .read_to_end(&mut self.pending)
+            .map_err(read_error)?;
+
+        let complete = match self.pending.iter().rposition(|b| *b == b'\n') {
+            Some(last_newline) => last_newline + 1,
+            None => return Ok(Batch::default()),
+        };
+
+        let mut batch = Batch::default();
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(&self.pending[..complete]);
+        for record in reader.records() {
+            match (record, &self.headers) {
+                (Err(e), _) => batch.malformed.push(format!("{:?}", e)),
+                (Ok(record), None) => self.headers = Some(record),
+                (Ok(record), Some(headers)) => {
+                    batch.rows += 1;
+                    match record.deserialize::<Transaction>(Some(headers)) {
+                        Ok(transaction) => {
+                            if let Err(e) = self.ledger.apply(&transaction) {
+                                batch.errors.push(e)
+                            }
+                        }
+                        Err(e) => batch.malformed.push(format!("{:?}", e)),
+                    }
+                }
+            }
+        }
+
+        self.pending.drain(..complete);
+        self.offset += complete as u64;
+        batch.warnings = self.ledger.take_warnings();
+        Ok(batch)
+    }
+
+    /*
+     * The state goes first. Dying before the output is replaced only means the
+     * output lags behind for a bit, the state is what we resume from.
+     * */
+    pub fn write_snapshot(&self, output: &str) -> io::Result<()> {
+        let config = self.ledger.config();
+        let mut accounts = vec![];
+        writer::write_accounts(
+            &self.ledger.accounts(),
+            &writer::columns(config),
+            config,
+            &mut accounts,
+        )?;
+
+        let mut state = format!("{}\n", self.offset).into_bytes();
+        state.extend_from_slice(&snapshot::encode(&Snapshot {
+            state: self.ledger.state(),
+            input: None,
+        }));
+        write_atomically(&state_path(output), &state)?;
+        write_atomically(output, &accounts)
+    }
+}
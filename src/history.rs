@@ -0,0 +1,147 @@
+use crate::account::Account;
+use crate::deposit::DepositStore;
+use crate::error::TransactionError;
+use crate::ledger::Ledger;
+use crate::transaction::Transaction;
+use std::collections::HashMap;
+use std::mem;
+
+/*
+ * Every state an account has been in, one version per transaction of its
+ * client, in order. A version remembers the transaction, wether it went
+ * through, and what the account looked like right after it. Versions are only
+ * ever appended, except when a transaction turns up that belongs somewhere in
+ * the past. Then every later version is recomputed, and replaced.
+ *
+ * Versions are ordered by a key, like a timestamp. Transactions with the same
+ * key keep the order they came in.
+ *
+ * Nothing is ever dropped, so this costs a little over a 100 bytes per
+ * transaction, on top of whatever the ledger itself keeps.
+ * */
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Version {
+    pub key: u64,
+    pub transaction: Transaction,
+    pub error: Option<TransactionError>,
+    pub account: Option<Account>,
+}
+
+/*
+ * A transaction that came after an inserted one, and went differently the
+ * second time around.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Changed {
+    pub transaction: Transaction,
+    pub before: Option<TransactionError>,
+    pub after: Option<TransactionError>,
+}
+
+/*
+ * What inserting a transaction in the past came down to.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub struct Recomputed {
+    pub result: Result<(), TransactionError>,
+    pub warnings: Vec<TransactionError>,
+    pub changed: Vec<Changed>,
+}
+
+#[derive(Default)]
+pub struct History {
+    clients: HashMap<u16, Vec<Version>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn versions(&self, client: u16) -> &[Version] {
+        self.clients.get(&client).map_or(&[], |versions| versions)
+    }
+
+    /*
+     * Applies a transaction that's at or after everything so far, and records
+     * the new version. Warnings are left in the ledger.
+     * */
+    pub fn apply<S: DepositStore>(
+        &mut self,
+        ledger: &mut Ledger<S>,
+        key: u64,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let result = ledger.apply(transaction);
+        self.clients
+            .entry(transaction.client)
+            .or_default()
+            .push(Version {
+                key,
+                transaction: *transaction,
+                error: result.clone().err(),
+                account: ledger.account(transaction.client),
+            });
+        result
+    }
+
+    /*
+     * Puts a transaction where it belongs, after everything with the same or a
+     * lower key, and replays the client from the start. The deposits the
+     * client's disputes refer to have to be in the state they were at that
+     * point, so there's no starting halfway. Only the inserted transaction's
+     * warnings are kept, the others were seen the first time around. If the
+     * ledger can't forget about the client, nothing is replayed, and the
+     * transaction is refused.
+     * */
+    pub fn insert<S: DepositStore>(
+        &mut self,
+        ledger: &mut Ledger<S>,
+        key: u64,
+        transaction: &Transaction,
+    ) -> Recomputed {
+        let versions = self.clients.entry(transaction.client).or_default();
+        let at = versions.partition_point(|version| version.key <= key);
+        let txs = versions
+            .iter()
+            .map(|version| version.transaction.tx)
+            .collect::<Vec<u32>>();
+        if let Err(e) = ledger.reset_client(transaction.client, txs) {
+            return Recomputed {
+                result: Err(TransactionError::StorageFailure(
+                    *transaction,
+                    format!("{:?}", e),
+                )),
+                warnings: vec![],
+                changed: vec![],
+            };
+        }
+        let later = versions.split_off(at);
+        let earlier = mem::take(versions);
+
+        earlier.iter().for_each(|version| {
+            self.apply(ledger, version.key, &version.transaction).ok();
+            ledger.take_warnings();
+        });
+        let result = self.apply(ledger, key, transaction);
+        let warnings = ledger.take_warnings();
+        let changed = later
+            .into_iter()
+            .filter_map(|version| {
+                let after = self.apply(ledger, version.key, &version.transaction).err();
+                ledger.take_warnings();
+                (after != version.error).then_some(Changed {
+                    transaction: version.transaction,
+                    before: version.error,
+                    after,
+                })
+            })
+            .collect();
+        Recomputed {
+            result,
+            warnings,
+            changed,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::account::Account;
+use crate::currency::{add, unsafe_subtract, Currency};
+use crate::deposit::DepositStore;
+use crate::error::TransactionError;
+use crate::ledger::Ledger;
+use crate::transaction::{Transaction, TransactionType};
+use std::collections::HashMap;
+use std::mem;
+
+/*
+ * What should always hold, whatever the input:
+ * - every account's total is what's available plus what's held,
+ * - nothing held is ever below 0,
+ * - all totals together are what was deposited, minus what was withdrawn,
+ *   minus what was charged back and isn't owed by the client instead.
+ *
+ * Available can go below 0, a deposit that's already spent can still be
+ * disputed. Every violation comes with the transaction that caused it, if it
+ * was found right after one.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Violation {
+    TotalMismatch(Option<Transaction>, Account),
+    NegativeHeld(Option<Transaction>, Account),
+    /* What the flows add up to, and what the accounts do */
+    SystemTotalMismatch(Option<Transaction>, Currency, Currency),
+}
+
+pub fn check_account(account: &Account, transaction: Option<Transaction>) -> Vec<Violation> {
+    let mut violations = vec![];
+    if add(account.available(), account.held()) != account.total() {
+        violations.push(Violation::TotalMismatch(transaction, *account));
+    }
+    if account.held().0 < 0 {
+        violations.push(Violation::NegativeHeld(transaction, *account));
+    }
+    violations
+}
+
+/*
+ * Keeps count of the money that came in and went out, apart from the ledger,
+ * to hold the accounts against. A chargeback takes out whatever its deposit
+ * was for, so every deposit's amount is kept around, 4 + 8 bytes each. A
+ * dispute that added to the client's receivable only held the rest, and only
+ * that is taken out if it's charged back, so those shortfalls are kept too.
+ * That only works when the checker saw every transaction the ledger did, from
+ * the start.
+ * */
+pub struct Checker {
+    deposits: HashMap<u32, Currency>,
+    shortfalls: HashMap<u32, Currency>,
+    flows: Currency,
+    totals: Currency,
+    violations: Vec<Violation>,
+}
+
+impl Default for Checker {
+    fn default() -> Checker {
+        Checker {
+            deposits: HashMap::new(),
+            shortfalls: HashMap::new(),
+            flows: Currency(0),
+            totals: Currency(0),
+            violations: vec![],
+        }
+    }
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker::default()
+    }
+
+    /*
+     * Applies a transaction, and checks the account it touched and the totals
+     * right after. That's cheap enough to do for every transaction.
+     * */
+    pub fn apply<S: DepositStore>(
+        &mut self,
+        ledger: &mut Ledger<S>,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let client = transaction.client;
+        let before = ledger.account(client);
+        let result = ledger.apply(transaction);
+        let after = ledger.account(client);
+        let total = |account: Option<Account>| account.map_or(Currency(0), |a| a.total());
+        let receivable = |account: Option<Account>| account.map_or(Currency(0), |a| a.receivable());
+        if result.is_ok() {
+            let moved = match transaction.tx_type {
+                TransactionType::Deposit => {
+                    self.deposits.insert(transaction.tx, transaction.amount);
+                    transaction.amount
+                }
+                TransactionType::Withdrawal => Currency(-transaction.amount.0),
+                TransactionType::Dispute => {
+                    let shortfall = unsafe_subtract(receivable(after), receivable(before));
+                    if shortfall.0 > 0 {
+                        self.shortfalls.insert(transaction.tx, shortfall);
+                    }
+                    Currency(0)
+                }
+                TransactionType::Resolve => {
+                    self.shortfalls.remove(&transaction.tx);
+                    Currency(0)
+                }
+                TransactionType::Chargeback => {
+                    let amount = self.deposits.get(&transaction.tx).copied();
+                    let owed = self.shortfalls.remove(&transaction.tx);
+                    unsafe_subtract(owed.unwrap_or(Currency(0)), amount.unwrap_or(Currency(0)))
+                }
+            };
+            self.flows = add(self.flows, moved);
+        }
+        self.totals = add(self.totals, unsafe_subtract(total(after), total(before)));
+
+        if let Some(account) = after {
+            self.violations
+                .append(&mut check_account(&account, Some(*transaction)));
+        }
+        if self.flows != self.totals {
+            self.violations.push(Violation::SystemTotalMismatch(
+                Some(*transaction),
+                self.flows,
+                self.totals,
+            ));
+            /* Only report it once, not for every transaction after */
+            self.totals = self.flows;
+        }
+        result
+    }
+
+    /* Taking them empties the list */
+    pub fn take_violations(&mut self) -> Vec<Violation> {
+        mem::take(&mut self.violations)
+    }
+
+    /*
+     * Goes over every account, and adds up the totals from scratch, rather
+     * than trusting what was kept track of along the way.
+     * */
+    pub fn check<S: DepositStore>(&self, ledger: &Ledger<S>) -> Vec<Violation> {
+        let accounts = ledger.accounts();
+        let mut violations = accounts
+            .iter()
+            .flat_map(|account| check_account(account, None))
+            .collect::<Vec<Violation>>();
+        let totals = accounts
+            .iter()
+            .fold(Currency(0), |sum, account| add(sum, account.total()));
+        if totals != self.flows {
+            violations.push(Violation::SystemTotalMismatch(None, self.flows, totals));
+        }
+        violations
+    }
+}
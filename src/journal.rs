@@ -0,0 +1,299 @@
+use crate::account::{new_from_client_id, Account};
+use crate::currency::{add, to_float_string, unsafe_subtract, Currency};
+use crate::error::TransactionError;
+use crate::ledger::Ledger;
+use crate::transaction::{Transaction, TransactionType};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+/*
+ * Double-entry bookkeeping next to the ledger. Every transaction that goes
+ * through becomes a journal entry that debits some books and credits others
+ * by the same amount. Money can then only ever move between books, never
+ * appear or vanish, and the trial balance proves it: all debits ever posted
+ * add up to all credits.
+ *
+ * There's a book for what every client has available, one for what they have
+ * held, one for the outside world money comes in from and goes out to, and one
+ * for what was lost to chargebacks:
+ *
+ *   deposit     debit funding          credit available
+ *   withdrawal  debit available        credit funding
+ *   dispute     debit available        credit held
+ *   resolve     debit held             credit available
+ *   chargeback  debit held             credit chargeback loss
+ *
+ * The amounts come from what the account actually did, not from the row, so an
+ * account that moved one way on one side and another way on the other gets an
+ * entry that doesn't balance.
+ * */
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub enum Book {
+    Available(u16),
+    Held(u16),
+    Funding,
+    ChargebackLoss,
+}
+
+impl fmt::Display for Book {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Book::Available(client) => write!(f, "available:{}", client),
+            Book::Held(client) => write!(f, "held:{}", client),
+            Book::Funding => write!(f, "funding"),
+            Book::ChargebackLoss => write!(f, "chargeback_loss"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Line {
+    pub book: Book,
+    pub debit: Currency,
+    pub credit: Currency,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    pub tx: u32,
+    pub lines: Vec<Line>,
+}
+
+impl Entry {
+    pub fn is_balanced(&self) -> bool {
+        let debits: i64 = self.lines.iter().map(|line| line.debit.0).sum();
+        let credits: i64 = self.lines.iter().map(|line| line.credit.0).sum();
+        debits == credits
+    }
+}
+
+fn debit(book: Book, amount: Currency) -> Line {
+    Line {
+        book,
+        debit: amount,
+        credit: Currency(0),
+    }
+}
+
+fn credit(book: Book, amount: Currency) -> Line {
+    Line {
+        book,
+        debit: Currency(0),
+        credit: amount,
+    }
+}
+
+/*
+ * The entry for a transaction that went through, from the account before and
+ * after it.
+ * */
+pub fn entry_for(transaction: &Transaction, before: Option<Account>, after: Account) -> Entry {
+    let client = transaction.client;
+    let before = before.unwrap_or_else(|| new_from_client_id(client));
+    let available = unsafe_subtract(after.available(), before.available());
+    let held = unsafe_subtract(after.held(), before.held());
+    let total = unsafe_subtract(after.total(), before.total());
+    let negative = |amount: Currency| Currency(-amount.0);
+    let lines = match transaction.tx_type {
+        TransactionType::Deposit => vec![
+            debit(Book::Funding, total),
+            credit(Book::Available(client), available),
+        ],
+        TransactionType::Withdrawal => vec![
+            debit(Book::Available(client), negative(available)),
+            credit(Book::Funding, negative(total)),
+        ],
+        TransactionType::Dispute => vec![
+            debit(Book::Available(client), negative(available)),
+            credit(Book::Held(client), held),
+        ],
+        TransactionType::Resolve => vec![
+            debit(Book::Held(client), negative(held)),
+            credit(Book::Available(client), available),
+        ],
+        TransactionType::Chargeback => vec![
+            debit(Book::Held(client), negative(held)),
+            credit(Book::ChargebackLoss, negative(total)),
+        ],
+    };
+    Entry {
+        tx: transaction.tx,
+        lines,
+    }
+}
+
+/*
+ * One book in the trial balance. The balance is debits minus credits, so the
+ * books clients' money is in come out negative, and funding positive.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Row {
+    pub book: Book,
+    pub debits: Currency,
+    pub credits: Currency,
+    pub balance: Currency,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct TrialBalance {
+    pub rows: Vec<Row>,
+    pub debits: Currency,
+    pub credits: Currency,
+}
+
+impl TrialBalance {
+    pub fn is_balanced(&self) -> bool {
+        self.debits == self.credits
+    }
+}
+
+#[derive(Serialize)]
+struct Written {
+    book: String,
+    #[serde(serialize_with = "to_float_string")]
+    debits: Currency,
+    #[serde(serialize_with = "to_float_string")]
+    credits: Currency,
+    #[serde(serialize_with = "to_float_string")]
+    balance: Currency,
+}
+
+/*
+ * As a CSV, one row per book and a last one with the totals.
+ * */
+pub fn write_trial_balance<W: io::Write>(
+    trial_balance: &TrialBalance,
+    writer: W,
+) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for row in &trial_balance.rows {
+        writer.serialize(Written {
+            book: row.book.to_string(),
+            debits: row.debits,
+            credits: row.credits,
+            balance: row.balance,
+        })?;
+    }
+    writer.serialize(Written {
+        book: "total".to_string(),
+        debits: trial_balance.debits,
+        credits: trial_balance.credits,
+        balance: unsafe_subtract(trial_balance.debits, trial_balance.credits),
+    })?;
+    writer.flush()?;
+    Ok(())
+}
+
+/*
+ * Only keeps the totals per book, not the entries themselves.
+ * */
+#[derive(Default)]
+pub struct Journal {
+    books: BTreeMap<Book, (Currency, Currency)>,
+    entries: u64,
+}
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal::default()
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /* Refuses an entry that doesn't balance, and leaves the books as they were */
+    pub fn post(&mut self, entry: &Entry) -> Result<(), Entry> {
+        if !entry.is_balanced() {
+            return Err(entry.clone());
+        }
+        entry.lines.iter().for_each(|line| {
+            let (debits, credits) = self
+                .books
+                .entry(line.book)
+                .or_insert((Currency(0), Currency(0)));
+            *debits = add(*debits, line.debit);
+            *credits = add(*credits, line.credit);
+        });
+        self.entries += 1;
+        Ok(())
+    }
+
+    /* Sorted by book, clients first */
+    pub fn trial_balance(&self) -> TrialBalance {
+        let rows = self
+            .books
+            .iter()
+            .map(|(book, (debits, credits))| Row {
+                book: *book,
+                debits: *debits,
+                credits: *credits,
+                balance: unsafe_subtract(*debits, *credits),
+            })
+            .collect::<Vec<Row>>();
+        TrialBalance {
+            debits: rows
+                .iter()
+                .fold(Currency(0), |sum, row| add(sum, row.debits)),
+            credits: rows
+                .iter()
+                .fold(Currency(0), |sum, row| add(sum, row.credits)),
+            rows,
+        }
+    }
+}
+
+/*
+ * A ledger that posts an entry to the journal for every transaction that goes
+ * through. If the entry doesn't balance, the account is put back the way it
+ * was and the transaction is reported as `Unbalanced`. The deposit it touched
+ * isn't put back, as there's no undoing that from out here, so a ledger that
+ * refused something shouldn't be trusted much further.
+ * */
+pub struct JournalingLedger {
+    ledger: Ledger,
+    journal: Journal,
+}
+
+impl JournalingLedger {
+    pub fn new(ledger: Ledger) -> JournalingLedger {
+        JournalingLedger {
+            ledger,
+            journal: Journal::new(),
+        }
+    }
+
+    pub fn accounts(&self) -> Vec<Account> {
+        self.ledger.accounts()
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<TransactionError> {
+        self.ledger.take_warnings()
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let client = transaction.client;
+        let before = self.ledger.account(client);
+        self.ledger.apply(transaction)?;
+        let after = match self.ledger.account(client) {
+            Some(after) => after,
+            None => return Ok(()),
+        };
+        self.journal
+            .post(&entry_for(transaction, before, after))
+            .map_err(|_| {
+                match before {
+                    Some(before) => self.ledger.restore_accounts(vec![before]),
+                    None => self.ledger.drop_account(client),
+                }
+                TransactionError::Unbalanced(*transaction)
+            })
+    }
+}
@@ -1,154 +1,773 @@
 use crate::account::{new_from_client_id, Account};
-use crate::currency::{add, safe_subtract_verbose, unsafe_subtract};
+use crate::clients::ClientConfig;
+use crate::currency::{unsafe_subtract, Currency};
+use crate::deposit::{Deposit, DepositState, DepositStore};
 use crate::error::{TransactionError, UnsettledReason};
+use crate::limits::{WithdrawalLimits, Withdrawals};
 use crate::option;
+use crate::rules::{self, Rule, Verdict};
+use crate::timestamp::Timestamp;
 use crate::transaction::{Transaction, TransactionType};
-use std::collections::HashMap;
+use crate::tx_table::TxTable;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::mem;
+use std::time::Duration;
+
+/*
+ * What to do with a deposit or withdrawal of exactly 0. It doesn't move any
+ * money, but it's most likely a mistake upstream, so we either let it through
+ * and keep a warning around, or reject it outright.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ZeroAmountPolicy {
+    Warn,
+    Reject,
+}
+
+/*
+ * How long a deposit can be disputed for. `Transactions(n)` means the deposit
+ * can be disputed by any of the n transactions that come after it, whichever
+ * client they belong to. `Time(d)` goes by the timestamps in the input: a
+ * deposit can be disputed until d has passed since the latest timestamp seen
+ * when it came in. Time only moves forward, so a row that's older than what
+ * came before it doesn't turn the clock back.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DisputeWindow {
+    Transactions(u64),
+    Time(Duration),
+}
+
+impl DisputeWindow {
+    /* In whatever it's counted in, see `Clock` */
+    pub fn length(&self) -> u64 {
+        match self {
+            DisputeWindow::Transactions(transactions) => *transactions,
+            DisputeWindow::Time(time) => time.as_millis() as u64,
+        }
+    }
+}
+
+/*
+ * Where a transaction is in the input: how many rows came before it, and the
+ * latest timestamp up to and including it.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Clock {
+    pub position: u64,
+    pub latest: Option<Timestamp>,
+}
+
+impl Clock {
+    /* In whatever the window is counted in, rows if there's none */
+    pub fn at(&self, window: Option<DisputeWindow>) -> u64 {
+        match window {
+            Some(DisputeWindow::Time(_)) => self.latest.map_or(0, |latest| latest.0),
+            _ => self.position,
+        }
+    }
+}
+
+/*
+ * What to do with a row whose timestamp is older than one that came before it.
+ * Rows without a timestamp are never out of order.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum OutOfOrderPolicy {
+    Allow,
+    Reject,
+}
+
+/*
+ * What to do with a dispute for more than is available, because the deposit
+ * was already spent:
+ * - `Allow` holds all of it anyway, and available goes below 0,
+ * - `Reject` refuses the dispute,
+ * - `Receivable` holds what's available, and adds the rest to what the client
+ *   owes. A resolve drops that again, a chargeback only takes what was held,
+ *   and the client keeps owing the rest.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum OverdraftPolicy {
+    Allow,
+    Reject,
+    Receivable,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Config {
+    pub zero_amount: ZeroAmountPolicy,
+    /* Deposits can be disputed forever if there's none */
+    pub dispute_window: Option<DisputeWindow>,
+    pub out_of_order: OutOfOrderPolicy,
+    pub overdraft: OverdraftPolicy,
+    /* Whoever isn't in here gets `ClientConfig::default()` */
+    pub clients: BTreeMap<u16, ClientConfig>,
+    /* For every client, unless they have their own */
+    pub withdrawal_limits: WithdrawalLimits,
+    /*
+     * What the withdrawal limits are counted over, the same way as a dispute
+     * window. All of the input if there's none.
+     * */
+    pub withdrawal_window: Option<DisputeWindow>,
+    /* Which of the built in rules every ledger starts out with */
+    pub rules: rules::Config,
+}
+
+impl Config {
+    pub fn client(&self, client: u16) -> ClientConfig {
+        self.clients.get(&client).copied().unwrap_or_default()
+    }
+
+    /* The client's own limits, and the global ones for what they don't have */
+    pub fn withdrawal_limits(&self, client: u16) -> WithdrawalLimits {
+        self.client(client)
+            .withdrawal_limits
+            .or(self.withdrawal_limits)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            zero_amount: ZeroAmountPolicy::Warn,
+            dispute_window: None,
+            out_of_order: OutOfOrderPolicy::Allow,
+            overdraft: OverdraftPolicy::Allow,
+            clients: BTreeMap::new(),
+            withdrawal_limits: WithdrawalLimits::default(),
+            withdrawal_window: None,
+            rules: rules::Config::default(),
+        }
+    }
+}
+
+/*
+ * Everything a run ends up with. Accounts are sorted by client, errors and
+ * warnings are in input order.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub struct Outcome {
+    pub accounts: Vec<Account>,
+    pub errors: Vec<TransactionError>,
+    pub warnings: Vec<TransactionError>,
+}
+
+/*
+ * Everything a ledger knows, in plain vectors, so it can be written out and
+ * picked up again later. Accounts are sorted by client, deposits and expired
+ * deposits by tx id, and whatever's waiting to expire or be forgotten in the
+ * order it will.
+ * Recent withdrawals are sorted by client, then by when they happened.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct State {
+    pub position: u64,
+    pub latest: Option<Timestamp>,
+    pub accounts: Vec<Account>,
+    pub deposits: Vec<(u32, Deposit)>,
+    pub expiring: Vec<(u64, u32)>,
+    pub expired: Vec<(u32, u16)>,
+    pub forgetting: Vec<(u64, u32)>,
+    pub shortfalls: Vec<(u32, Currency)>,
+    pub withdrawn: Vec<(u16, u64, Currency)>,
+    pub withdrawals: Vec<(u16, u64, Currency)>,
+    /* What every rule remembers, by name, sorted by client */
+    pub rules: Vec<(String, Vec<(u16, u64)>)>,
+}
+
+/*
+ * We cache:
+ * - deposits, as they're the only transaction that can reasonably be disputed automatically.
+ *     Only what's needed to dispute them is kept, including wether they're
+ *     disputed right now, so resolves and chargebacks only ever handle things
+ *     that are actually disputed. See `Deposit`.
+ * - accounts: are created on demand, whenever a deposit occurs.
+ *     NOT for anything else, imho - it doesn't make sense to keep accounts
+ *     lingering around that don't have any funds, and are only trying to
+ *     withdraw / dispute / resolve / chargeback
+ * By design, any dispute refers to a deposit. A deposit can only be disputed
+ * by the client it belongs to, so every transaction only ever touches the
+ * state of a single client.
+ *
+ * Client ids are u16s, so there are at most 65536 accounts. Rather than hashing
+ * them, every client gets a slot in a table that's allocated once upfront, and
+ * accounts are updated in place. That's about 2.5MB, whatever the input.
+ * Deposits are keyed by tx id, in memory in a `TxTable` unless another
+ * `DepositStore` is handed in.
+ *
+ * With a dispute window, deposits are dropped once it closes, in the order
+ * they came in. A deposit that's disputed right at that moment stays around
+ * until it's resolved or charged back. For every dropped deposit we only
+ * remember who it belonged to, so a late dispute can be told apart from one
+ * for a deposit that never existed. That's 4 bytes instead of 16, and only for
+ * another window: after that, a dispute for it is one for a deposit we never
+ * heard of, and its tx id can be used again. So everything that's kept about
+ * deposits is bounded by the window.
+ *
+ * A dispute that could only hold part of its deposit keeps the shortfall
+ * around until it's resolved or charged back, so only what it held is undone.
+ *
+ * With withdrawal limits, the withdrawals that went through are kept per
+ * client for as long as they count towards them. See `Withdrawals`.
+ */
+const CLIENTS: usize = u16::MAX as usize + 1;
+
+pub struct Ledger<S: DepositStore = TxTable<Deposit>> {
+    config: Config,
+    deposits: S,
+    accounts: Box<[Option<Account>]>,
+    warnings: Vec<TransactionError>,
+    position: u64,
+    latest: Option<Timestamp>,
+    expiring: VecDeque<(u64, u32)>,
+    expired: TxTable<u16>,
+    forgetting: VecDeque<(u64, u32)>,
+    shortfalls: TxTable<Currency>,
+    withdrawals: Withdrawals,
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Ledger {
+    pub fn new(config: Config) -> Ledger {
+        Ledger::with_store(config, TxTable::new())
+    }
 
-pub fn parse_transactions(
-    transactions: &Vec<Transaction>,
-) -> (Vec<Account>, Vec<TransactionError>) {
     /*
-     * We cache:
-     * - deposits, as they're the only transaction that can reasonably be disputed automatically
-     * - disputes: to check that when we're resolving, or doing a chargeback, we're only
-     *     handling things that are actually disputed
-     * - accounts: are created on demand, whenever a deposit occurs.
-     *     NOT for anything else, imho - it doesn't make sense to keep accounts
-     *     lingering around that don't have any funds, and are only trying to
-     *     withdraw / dispute / resolve / chargeback
-     * By design, any dispute refers to a deposit. We could eventually add a
-     * flag as to wether it was disputed or not, which would be more memory efficient.
-     */
-    let mut deposits: HashMap<u32, Transaction> = HashMap::new();
-    let mut disputes: HashMap<u32, Transaction> = HashMap::new();
-    let mut accounts: HashMap<u16, Account> = HashMap::new();
-    let mut transaction_errors: Vec<TransactionError> = vec![];
-
-    transactions.iter().for_each(|transaction| {
-        let new_account = match &transaction.tx_type {
-            /* Deposit */
+     * Only for deposits kept in memory. A `DepositStore` can't be walked, and
+     * one on disk is gone after the run anyway.
+     * */
+    pub fn state(&self) -> State {
+        State {
+            position: self.position,
+            latest: self.latest,
+            accounts: self.accounts(),
+            deposits: self
+                .deposits
+                .iter()
+                .map(|(tx, deposit)| (tx, *deposit))
+                .collect(),
+            expiring: self.expiring.iter().copied().collect(),
+            expired: self
+                .expired
+                .iter()
+                .map(|(tx, client)| (tx, *client))
+                .collect(),
+            forgetting: self.forgetting.iter().copied().collect(),
+            shortfalls: self
+                .shortfalls
+                .iter()
+                .map(|(tx, shortfall)| (tx, *shortfall))
+                .collect(),
+            withdrawn: self.withdrawals.totals().collect(),
+            withdrawals: self.withdrawals.iter().collect(),
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| {
+                    let mut remembered = rule.remembered();
+                    remembered.sort_unstable();
+                    (rule.name().to_string(), remembered)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_state(config: Config, state: State) -> Ledger {
+        let mut ledger = Ledger::new(config);
+        ledger.set_position(state.position, state.latest);
+        ledger.restore_accounts(state.accounts);
+        state.deposits.into_iter().for_each(|(tx, deposit)| {
+            ledger.deposits.insert(tx, deposit);
+        });
+        ledger.expiring = state.expiring.into_iter().collect();
+        state.expired.into_iter().for_each(|(tx, client)| {
+            ledger.expired.insert(tx, client);
+        });
+        ledger.forgetting = state.forgetting.into_iter().collect();
+        state.shortfalls.into_iter().for_each(|(tx, shortfall)| {
+            ledger.shortfalls.insert(tx, shortfall);
+        });
+        state
+            .withdrawn
+            .into_iter()
+            .for_each(|(client, count, total)| {
+                ledger.withdrawals.restore_totals(client, count, total)
+            });
+        state
+            .withdrawals
+            .into_iter()
+            .for_each(|(client, at, amount)| ledger.withdrawals.restore(client, at, amount));
+        state.rules.into_iter().for_each(|(name, remembered)| {
+            if let Some(rule) = ledger.rules.iter_mut().find(|rule| rule.name() == name) {
+                remembered
+                    .into_iter()
+                    .for_each(|(client, value)| rule.remember(client, value));
+            }
+        });
+        ledger
+    }
+}
+
+impl<S: DepositStore> Ledger<S> {
+    pub fn with_store(config: Config, deposits: S) -> Ledger<S> {
+        Ledger {
+            rules: rules::built_in(&config.rules),
+            config,
+            deposits,
+            accounts: vec![None; CLIENTS].into_boxed_slice(),
+            warnings: vec![],
+            position: 0,
+            latest: None,
+            expiring: VecDeque::new(),
+            expired: TxTable::new(),
+            forgetting: VecDeque::new(),
+            shortfalls: TxTable::new(),
+            withdrawals: Withdrawals::new(),
+        }
+    }
+
+    /*
+     * Where the next transaction is in the input, and the latest timestamp
+     * before it. Only needed when not every transaction of the input goes
+     * through this ledger, like with shards, so dispute windows and ordering
+     * are still judged over the whole input.
+     * */
+    pub fn set_position(&mut self, position: u64, latest: Option<Timestamp>) {
+        self.position = position;
+        self.latest = latest;
+    }
+
+    /* How many rows went through so far */
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /*
+     * Rules are asked about every transaction, in the order they were added,
+     * after the ones from the config. See `Rule`.
+     * */
+    pub fn add_rule(&mut self, rule: Box<dyn Rule>) {
+        self.rules.push(rule);
+    }
+
+    /* Sorted by client */
+    pub fn accounts(&self) -> Vec<Account> {
+        self.accounts.iter().flatten().copied().collect()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn account(&self, client: u16) -> Option<Account> {
+        self.accounts[client as usize]
+    }
+
+    pub fn into_accounts(self) -> Vec<Account> {
+        self.accounts()
+    }
+
+    /*
+     * Puts previously written accounts back in place. Only the accounts are
+     * restored, so deposits from before can't be disputed anymore.
+     * */
+    pub fn restore_accounts(&mut self, accounts: Vec<Account>) {
+        accounts.into_iter().for_each(|account| {
+            self.accounts[account.client() as usize] = Some(account);
+        })
+    }
+
+    /*
+     * Forgets everything about a client, so its transactions can be applied
+     * again from scratch: the account, its deposits among the given tx ids,
+     * along with their shortfalls and when they expire, its recent withdrawals
+     * and whatever the rules remember of it. The tx ids are the client's
+     * transactions so far, a `DepositStore` can't be searched by client.
+     * */
+    pub fn reset_client(
+        &mut self,
+        client: u16,
+        txs: impl IntoIterator<Item = u32>,
+    ) -> std::io::Result<()> {
+        let mut forgotten = HashSet::new();
+        for tx in txs {
+            if self
+                .deposits
+                .get(tx)?
+                .is_some_and(|deposit| deposit.client == client)
+            {
+                self.deposits.remove(tx)?;
+                self.shortfalls.remove(tx);
+                forgotten.insert(tx);
+            }
+            if self.expired.get(tx) == Some(&client) {
+                self.expired.remove(tx);
+                forgotten.insert(tx);
+            }
+        }
+        self.expiring.retain(|(_, tx)| !forgotten.contains(tx));
+        self.forgetting.retain(|(_, tx)| !forgotten.contains(tx));
+        self.withdrawals.reset(client);
+        self.rules.iter_mut().for_each(|rule| rule.reset(client));
+        self.accounts[client as usize] = None;
+        Ok(())
+    }
+
+    /* Drops just the account, as if it was never opened */
+    pub fn drop_account(&mut self, client: u16) {
+        self.accounts[client as usize] = None;
+    }
+
+    /*
+     * Warnings are transactions that did go through, but look suspicious.
+     * Taking them empties the list.
+     * */
+    pub fn take_warnings(&mut self) -> Vec<TransactionError> {
+        mem::take(&mut self.warnings)
+    }
+
+    /*
+     * Money only ever moves in one direction per transaction type, so anything
+     * below 0 is rejected before it gets anywhere near an account. A warning
+     * is handed back, it's only kept if the transaction goes through.
+     * */
+    fn check_amount(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Option<TransactionError>, TransactionError> {
+        match transaction.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                if transaction.amount.0 < 0 {
+                    Err(TransactionError::NegativeAmount(*transaction))
+                } else if transaction.amount.0 == 0 {
+                    match self.config.zero_amount {
+                        ZeroAmountPolicy::Reject => Err(TransactionError::ZeroAmount(*transaction)),
+                        ZeroAmountPolicy::Warn => {
+                            Ok(Some(TransactionError::ZeroAmount(*transaction)))
+                        }
+                    }
+                } else {
+                    Ok(None)
+                }
+            }
+            /* These don't carry an amount of their own */
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                Ok(None)
+            }
+        }
+    }
+
+    /*
+     * Drops every deposit whose dispute window closed by `now`, and forgets
+     * about the ones whose window closed a window before that.
+     * */
+    fn expire(&mut self, now: u64) -> std::io::Result<()> {
+        while let Some(&(forgotten, tx)) = self.forgetting.front() {
+            if forgotten > now {
+                break;
+            }
+            self.forgetting.pop_front();
+            self.expired.remove(tx);
+        }
+        let window = match self.config.dispute_window {
+            Some(window) => window.length(),
+            None => return Ok(()),
+        };
+        while let Some(&(closes, tx)) = self.expiring.front() {
+            if closes > now {
+                break;
+            }
+            self.expiring.pop_front();
+            if let Some(deposit) = self.deposits.get(tx)? {
+                self.expired.insert(tx, deposit.client);
+                /* A disputed one is forgotten a window after it's settled */
+                if deposit.state != DepositState::Disputed {
+                    self.forgetting.push_back((closes + window, tx));
+                    self.deposits.remove(tx)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Debug builds check the account the transaction touched, every time. See
+     * `invariants` for the rest.
+     * */
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let result = self.apply_unchecked(transaction);
+        #[cfg(debug_assertions)]
+        if let Some(account) = self.accounts[transaction.client as usize] {
+            let violations = crate::invariants::check_account(&account, Some(*transaction));
+            debug_assert!(violations.is_empty(), "{:?}", violations);
+        }
+        result
+    }
+
+    /*
+     * The deposit is always stored before the account is touched, so a store
+     * that fails halfway never leaves the two disagreeing.
+     * */
+    fn apply_unchecked(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let out_of_order = transaction.timestamp.is_some() && transaction.timestamp < self.latest;
+        self.latest = self.latest.max(transaction.timestamp);
+        let clock = Clock {
+            position: self.position,
+            latest: self.latest,
+        };
+        let now = clock.at(self.config.dispute_window);
+        self.position += 1;
+        self.expire(now)
+            .map_err(|e| TransactionError::StorageFailure(*transaction, format!("{:?}", e)))?;
+        if out_of_order && self.config.out_of_order == OutOfOrderPolicy::Reject {
+            return Err(TransactionError::OutOfOrder(*transaction));
+        }
+        let warning = self.check_amount(transaction)?;
+        let flags = self.judge(transaction, clock)?;
+        self.settle(transaction, now, clock.at(self.config.withdrawal_window))?;
+        self.warnings.extend(warning);
+        self.warnings.extend(flags);
+        self.rules
+            .iter_mut()
+            .for_each(|rule| rule.applied(transaction, clock));
+        Ok(())
+    }
+
+    /*
+     * The first rule to deny the transaction has the last word, otherwise
+     * whatever the rules flagged is handed back, to be kept as warnings once
+     * the transaction went through.
+     * */
+    fn judge(
+        &mut self,
+        transaction: &Transaction,
+        clock: Clock,
+    ) -> Result<Vec<TransactionError>, TransactionError> {
+        let account = self.accounts[transaction.client as usize].as_ref();
+        let mut flags = vec![];
+        for rule in self.rules.iter_mut() {
+            match rule.check(transaction, account, clock) {
+                Verdict::Allow => (),
+                Verdict::Deny(reason) => {
+                    return Err(TransactionError::Denied(
+                        *transaction,
+                        rule.name().to_string(),
+                        reason,
+                    ))
+                }
+                Verdict::Flag(reason) => flags.push(TransactionError::Flagged(
+                    *transaction,
+                    rule.name().to_string(),
+                    reason,
+                )),
+            }
+        }
+        Ok(flags)
+    }
+
+    /* Where the money actually moves */
+    fn settle(
+        &mut self,
+        transaction: &Transaction,
+        now: u64,
+        withdrawn_at: u64,
+    ) -> Result<(), TransactionError> {
+        let storage_error =
+            |e: std::io::Error| TransactionError::StorageFailure(*transaction, format!("{:?}", e));
+        let known = match &transaction.tx_type {
+            TransactionType::Withdrawal => None,
+            _ => self.deposits.get(transaction.tx).map_err(storage_error)?,
+        };
+        let deposit = match &transaction.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+            _ => known.filter(|deposit| deposit.client == transaction.client),
+        };
+        let known = known.is_some() || self.expired.get(transaction.tx).is_some();
+        let expired = self
+            .expired
+            .get(transaction.tx)
+            .is_some_and(|client| *client == transaction.client);
+        let deposits = &mut self.deposits;
+        let forgetting = &mut self.forgetting;
+        let window = self.config.dispute_window.map(|window| window.length());
+        /* Once out of its window, a settled deposit has nothing left to do */
+        let mut store = |state: DepositState, deposit: Deposit| {
+            if expired && state != DepositState::Disputed {
+                forgetting.push_back((now + window.unwrap_or(0), transaction.tx));
+                deposits.remove(transaction.tx).map(|_| ())
+            } else {
+                deposits.insert(transaction.tx, Deposit { state, ..deposit })
+            }
+            .map_err(storage_error)
+        };
+
+        let overdraft = self.config.overdraft;
+        let credit_limit = self.config.client(transaction.client).credit_limit;
+        let limits = self.config.withdrawal_limits(transaction.client);
+        let withdrawal_window = self.config.withdrawal_window.map(|window| window.length());
+        let withdrawals = &mut self.withdrawals;
+        let shortfalls = &mut self.shortfalls;
+        let account = &mut self.accounts[transaction.client as usize];
+        match &transaction.tx_type {
+            /* Deposit, never over one that's already there, in any state */
             TransactionType::Deposit => {
-                deposits.insert(transaction.tx, *transaction);
-                accounts.get(&transaction.client).map_or(
-                    Ok(Account {
-                        available: transaction.amount,
-                        total: transaction.amount,
-                        ..new_from_client_id(transaction.client)
-                    }),
-                    |account| {
-                        Ok(Account {
-                            available: add(account.available, transaction.amount),
-                            total: add(account.total, transaction.amount),
-                            ..*account
-                        })
+                if known {
+                    return Err(TransactionError::DuplicateTransaction(*transaction));
+                }
+                store(
+                    DepositState::Deposited,
+                    Deposit {
+                        amount: transaction.amount,
+                        client: transaction.client,
+                        state: DepositState::Deposited,
                     },
-                )
+                )?;
+                if let Some(window) = self.config.dispute_window.map(|window| window.length()) {
+                    self.expiring.push_back((now + window + 1, transaction.tx));
+                }
+                let account = account.get_or_insert_with(|| new_from_client_id(transaction.client));
+                account.credit(transaction.amount);
+                Ok(())
             }
             /* Withdrawal */
-            TransactionType::Withdrawal => accounts.get(&transaction.client).map_or(
+            TransactionType::Withdrawal => account.as_mut().map_or(
                 Err(TransactionError::AccountlessAction(*transaction)),
                 |account| {
-                    option::sequence((
-                        safe_subtract_verbose(account.available, transaction.amount),
-                        safe_subtract_verbose(account.total, transaction.amount),
-                    ))
-                    .map_or(
-                        Err(TransactionError::UnsettledWithdrawal(
-                            *transaction,
-                            UnsettledReason::InsufficientFunds,
-                        )),
-                        |(available, total)| {
-                            Ok(Account {
-                                available,
-                                total,
-                                ..*account
-                            })
-                        },
-                    )
+                    withdrawals
+                        .check(
+                            transaction.client,
+                            transaction.amount,
+                            withdrawn_at,
+                            withdrawal_window,
+                            &limits,
+                        )
+                        .map_err(|limit| TransactionError::WithdrawalLimit(*transaction, limit))?;
+                    account
+                        .debit(transaction.amount, credit_limit)
+                        .map_err(|reason| {
+                            TransactionError::UnsettledWithdrawal(*transaction, reason)
+                        })?;
+                    withdrawals.record(
+                        transaction.client,
+                        withdrawn_at,
+                        transaction.amount,
+                        withdrawal_window,
+                        &limits,
+                    );
+                    Ok(())
                 },
             ),
             /* Dispute */
-            TransactionType::Dispute => option::sequence((
-                accounts.get(&transaction.client),
-                deposits.get(&transaction.tx),
-            ))
-            .map(|(account, past_transaction)| {
-                disputes.insert(past_transaction.tx, *past_transaction);
-                Ok(Account {
-                    available: unsafe_subtract(account.available, past_transaction.amount),
-                    held: add(account.held, past_transaction.amount),
-                    ..*account
-                })
-            })
-            .unwrap_or(Err(TransactionError::UnsettledDispute(
-                *transaction,
-                UnsettledReason::TransactionOrAccountNotFound,
-            ))),
+            TransactionType::Dispute => match option::sequence((account.as_mut(), deposit)) {
+                None if expired => Err(TransactionError::DisputeWindowExpired(*transaction)),
+                None => Err(TransactionError::UnsettledDispute(
+                    *transaction,
+                    UnsettledReason::TransactionOrAccountNotFound,
+                )),
+                Some((_, deposit)) if deposit.state != DepositState::Deposited => {
+                    Err(TransactionError::UnsettledDispute(
+                        *transaction,
+                        UnsettledReason::InvalidState(deposit.state),
+                    ))
+                }
+                Some((account, deposit)) => {
+                    let mut held = *account;
+                    let dispute_error =
+                        |reason| TransactionError::UnsettledDispute(*transaction, reason);
+                    let shortfall = match overdraft {
+                        OverdraftPolicy::Allow => {
+                            held.hold(deposit.amount);
+                            Currency(0)
+                        }
+                        OverdraftPolicy::Reject => {
+                            held.hold_available(deposit.amount).map_err(dispute_error)?;
+                            Currency(0)
+                        }
+                        OverdraftPolicy::Receivable => held.hold_owing(deposit.amount),
+                    };
+                    store(DepositState::Disputed, deposit)?;
+                    if shortfall.0 > 0 {
+                        shortfalls.insert(transaction.tx, shortfall);
+                    }
+                    *account = held;
+                    Ok(())
+                }
+            },
             /* Resolve */
-            TransactionType::Resolve => option::sequence((
-                accounts.get(&transaction.client),
-                disputes.get(&transaction.tx),
-            ))
-            .map(|(account, past_transaction)| {
-                match safe_subtract_verbose(account.held, past_transaction.amount) {
-                    Some(held) => Ok(Account {
-                        available: add(account.available, past_transaction.amount),
-                        held,
-                        ..*account
-                    }),
-                    None => Err(TransactionError::UnsettledResolve(
+            TransactionType::Resolve => match option::sequence((account.as_mut(), deposit)) {
+                None => Err(TransactionError::UnsettledResolve(
+                    *transaction,
+                    UnsettledReason::TransactionOrAccountNotFound,
+                )),
+                Some((_, deposit)) if deposit.state != DepositState::Disputed => {
+                    Err(TransactionError::UnsettledResolve(
                         *transaction,
-                        UnsettledReason::InsufficientFunds,
-                    )),
+                        UnsettledReason::InvalidState(deposit.state),
+                    ))
+                }
+                Some((account, deposit)) => {
+                    /* Tried on a copy first, the deposit is stored before the account changes */
+                    let mut released = *account;
+                    let shortfall = shortfalls.get(transaction.tx).copied();
+                    released
+                        .release_owing(deposit.amount, shortfall.unwrap_or(Currency(0)))
+                        .map_err(|reason| {
+                            TransactionError::UnsettledResolve(*transaction, reason)
+                        })?;
+                    store(DepositState::Deposited, deposit)?;
+                    shortfalls.remove(transaction.tx);
+                    *account = released;
+                    Ok(())
                 }
-            })
-            .unwrap_or(Err(TransactionError::UnsettledResolve(
-                *transaction,
-                UnsettledReason::TransactionOrAccountNotFound,
-            ))),
+            },
             /* Chargeback */
-            TransactionType::Chargeback => option::sequence((
-                accounts.get(&transaction.client),
-                disputes.get(&transaction.tx),
-            ))
-            .map(|(account, past_transaction)| {
-                match option::sequence((
-                    safe_subtract_verbose(account.held, past_transaction.amount),
-                    safe_subtract_verbose(account.total, past_transaction.amount),
-                )) {
-                    Some((held, total)) => Ok(Account {
-                        held,
-                        total,
-                        locked: true,
-                        ..*account
-                    }),
-                    None => Err(TransactionError::UnsettledChargeback(
+            TransactionType::Chargeback => match option::sequence((account.as_mut(), deposit)) {
+                None => Err(TransactionError::UnsettledChargeback(
+                    *transaction,
+                    UnsettledReason::TransactionOrAccountNotFound,
+                )),
+                Some((_, deposit)) if deposit.state != DepositState::Disputed => {
+                    Err(TransactionError::UnsettledChargeback(
                         *transaction,
-                        UnsettledReason::InsufficientFunds,
-                    )),
+                        UnsettledReason::InvalidState(deposit.state),
+                    ))
                 }
-            })
-            .unwrap_or(Err(TransactionError::UnsettledChargeback(
-                *transaction,
-                UnsettledReason::TransactionOrAccountNotFound,
-            ))),
-        };
-
-        /*
-         * If we were able to create the new account variant, we should add it.
-         */
-        match new_account {
-            Ok(account) => {
-                accounts.insert(transaction.client, account);
-                ()
-            }
-            Err(e) => transaction_errors.push(e),
+                Some((account, deposit)) => {
+                    /* Whatever wasn't held stays owed */
+                    let mut reversed = *account;
+                    let shortfall = shortfalls.get(transaction.tx).copied();
+                    reversed
+                        .reverse(
+                            unsafe_subtract(deposit.amount, shortfall.unwrap_or(Currency(0))),
+                            credit_limit,
+                        )
+                        .map_err(|reason| {
+                            TransactionError::UnsettledChargeback(*transaction, reason)
+                        })?;
+                    store(DepositState::ChargedBack, deposit)?;
+                    shortfalls.remove(transaction.tx);
+                    *account = reversed;
+                    Ok(())
+                }
+            },
         }
-    });
+    }
+}
+
+pub fn parse_transactions(transactions: &[Transaction]) -> (Vec<Account>, Vec<TransactionError>) {
+    parse_transactions_with_config(transactions, Config::default())
+}
+
+pub fn parse_transactions_with_config(
+    transactions: &[Transaction],
+    config: Config,
+) -> (Vec<Account>, Vec<TransactionError>) {
+    let mut ledger = Ledger::new(config);
+    let transaction_errors = transactions
+        .iter()
+        .filter_map(|transaction| ledger.apply(transaction).err())
+        .collect::<Vec<TransactionError>>();
 
-    (
-        accounts.into_values().collect::<Vec<Account>>(),
-        transaction_errors,
-    )
+    (ledger.into_accounts(), transaction_errors)
 }
@@ -4,13 +4,36 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 use std::io::{self, Write};
 
 pub mod account;
+pub mod checkpoint;
+pub mod clients;
+pub mod config;
 pub mod currency;
+pub mod deposit;
+pub mod disk_store;
 pub mod error;
+pub mod events;
+pub mod follow;
+pub mod history;
+pub mod invariants;
+pub mod journal;
 pub mod ledger;
+pub mod limits;
+pub mod mmap_reader;
 pub mod option;
+pub mod parallel_reader;
+pub mod query;
 pub mod reader;
+pub mod reorder;
+pub mod rules;
+pub mod sharded_ledger;
+pub mod snapshot;
 pub mod tests;
+pub mod timestamp;
 pub mod transaction;
+pub mod tx_table;
+pub mod validation;
+pub mod wal;
+pub mod writer;
 
 pub fn bench(filepath: &str) {
     match reader::read_file_from_path(filepath) {
@@ -31,13 +54,13 @@ pub fn bench(filepath: &str) {
             });
 
             /* This is rather unreasonable, should handle errs properly */
-            let std_output = &writer.into_inner().unwrap_or(vec![]);
+            let std_output = &writer.into_inner().unwrap_or_default();
             let _ = io::stdout().write_all(std_output);
 
             /* --------------------- */
             /* Write errors if any   */
             /* --------------------- */
-            if failed_transactions.len() > 0 {
+            if !failed_transactions.is_empty() {
                 eprintln!("Some transactions could not be handled. See output below:");
                 failed_transactions
                     .iter()
@@ -0,0 +1,157 @@
+use crate::currency::{add, unsafe_subtract, Currency};
+use std::collections::{BTreeMap, VecDeque};
+
+/*
+ * Caps on what a client can withdraw: any single withdrawal, and how many
+ * withdrawals and how much in total within the withdrawal window, see
+ * `Config::withdrawal_window`. Every one of them is off when it's `None`.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct WithdrawalLimits {
+    pub max_amount: Option<Currency>,
+    pub max_count: Option<u64>,
+    pub max_total: Option<Currency>,
+}
+
+impl WithdrawalLimits {
+    /* Ours where they're set, the others' where they aren't */
+    pub fn or(self, others: WithdrawalLimits) -> WithdrawalLimits {
+        WithdrawalLimits {
+            max_amount: self.max_amount.or(others.max_amount),
+            max_count: self.max_count.or(others.max_count),
+            max_total: self.max_total.or(others.max_total),
+        }
+    }
+}
+
+/* The limit a withdrawal would have broken, and what it's set to */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Limit {
+    Amount(Currency),
+    Count(u64),
+    Total(Currency),
+}
+
+/*
+ * The withdrawals that went through, per client, with when they did in
+ * whatever the window is counted in. Only the ones still in the window are
+ * kept around, and only once there's a limit that needs them. Without a window
+ * every withdrawal counts forever, so only how many there were and how much
+ * they came to is kept, not the withdrawals themselves.
+ * */
+#[derive(Debug, Default)]
+pub struct Withdrawals {
+    recent: BTreeMap<u16, Recent>,
+}
+
+/* What's in the window for a client, and what it all adds up to */
+#[derive(Debug, Default)]
+struct Recent {
+    count: u64,
+    total: Currency,
+    withdrawals: VecDeque<(u64, Currency)>,
+}
+
+impl Withdrawals {
+    pub fn new() -> Withdrawals {
+        Withdrawals::default()
+    }
+
+    /*
+     * Whether a withdrawal at `now` stays within the limits, counting the
+     * ones that came less than `window` before it. Without a window, every
+     * one before it counts.
+     * */
+    pub fn check(
+        &mut self,
+        client: u16,
+        amount: Currency,
+        now: u64,
+        window: Option<u64>,
+        limits: &WithdrawalLimits,
+    ) -> Result<(), Limit> {
+        if let Some(max) = limits.max_amount.filter(|max| amount.0 > max.0) {
+            return Err(Limit::Amount(max));
+        }
+        if limits.max_count.is_none() && limits.max_total.is_none() {
+            return Ok(());
+        }
+        let recent = self.recent.entry(client).or_default();
+        if let Some(window) = window {
+            while let Some(&(at, amount)) = recent.withdrawals.front() {
+                if at.saturating_add(window) > now {
+                    break;
+                }
+                recent.withdrawals.pop_front();
+                recent.count -= 1;
+                recent.total = unsafe_subtract(recent.total, amount);
+            }
+        }
+        if let Some(max) = limits.max_count.filter(|max| recent.count >= *max) {
+            return Err(Limit::Count(max));
+        }
+        if let Some(max) = limits
+            .max_total
+            .filter(|max| add(recent.total, amount).0 > max.0)
+        {
+            return Err(Limit::Total(max));
+        }
+        Ok(())
+    }
+
+    /* A withdrawal that went through, after `check` let it */
+    pub fn record(
+        &mut self,
+        client: u16,
+        now: u64,
+        amount: Currency,
+        window: Option<u64>,
+        limits: &WithdrawalLimits,
+    ) {
+        if limits.max_count.is_some() || limits.max_total.is_some() {
+            let recent = self.recent.entry(client).or_default();
+            recent.count += 1;
+            recent.total = add(recent.total, amount);
+            if window.is_some() {
+                recent.withdrawals.push_back((now, amount));
+            }
+        }
+    }
+
+    /* How many withdrawals count for every client, and how much they add up to */
+    pub fn totals(&self) -> impl Iterator<Item = (u16, u64, Currency)> + '_ {
+        self.recent
+            .iter()
+            .map(|(client, recent)| (*client, recent.count, recent.total))
+    }
+
+    /* Sorted by client, then oldest first */
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u64, Currency)> + '_ {
+        self.recent.iter().flat_map(|(client, recent)| {
+            recent
+                .withdrawals
+                .iter()
+                .map(move |&(at, amount)| (*client, at, amount))
+        })
+    }
+
+    /* Forgets about the client's withdrawals, as if there never were any */
+    pub fn reset(&mut self, client: u16) {
+        self.recent.remove(&client);
+    }
+
+    /* The totals go first, the withdrawals in the window don't add to them */
+    pub fn restore_totals(&mut self, client: u16, count: u64, total: Currency) {
+        let recent = self.recent.entry(client).or_default();
+        recent.count = count;
+        recent.total = total;
+    }
+
+    pub fn restore(&mut self, client: u16, at: u64, amount: Currency) {
+        self.recent
+            .entry(client)
+            .or_default()
+            .withdrawals
+            .push_back((at, amount));
+    }
+}
@@ -2,57 +2,610 @@
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use std::io::{self, Write};
-use std::{env, process};
+use std::time::Duration;
+use std::{env, process, thread};
 
 pub mod account;
+pub mod checkpoint;
+pub mod clients;
+pub mod config;
 pub mod currency;
+pub mod deposit;
+pub mod disk_store;
 pub mod error;
+pub mod events;
+pub mod follow;
+pub mod history;
+pub mod invariants;
+pub mod journal;
 pub mod ledger;
+pub mod limits;
+pub mod mmap_reader;
 pub mod option;
+pub mod parallel_reader;
+pub mod query;
 pub mod reader;
+pub mod reorder;
+pub mod rules;
+pub mod sharded_ledger;
+pub mod snapshot;
+pub mod timestamp;
 pub mod transaction;
+pub mod tx_table;
+pub mod validation;
+pub mod wal;
+pub mod writer;
 
-fn main() {
-    env::args().collect::<Vec<String>>().get(1).map_or_else(
-        || eprintln!("Please provide a path to the CSV file with transactions"),
-        |filepath| match reader::read_file_from_path(filepath) {
-            Ok(transactions) => {
-                let (parsed_transactions, failed_transactions) =
-                    ledger::parse_transactions(&transactions);
-
-                /* --------------------- */
-                /* Write correct results */
-                /* --------------------- */
-                let mut writer = csv::Writer::from_writer(vec![]);
-                parsed_transactions.iter().for_each(|x| {
-                    /*
-                     * I'm ignoring the error's here, rely'ing on the soundness of my types,
-                     * ideally, this should be handled correctly.
-                     * */
-                    let _ = writer.serialize(x);
-                });
-
-                /* This is rather unreasonable, should handle errs properly */
-                let std_output = &writer.into_inner().unwrap_or(vec![]);
-                let _ = io::stdout().write_all(std_output);
-
-                /* --------------------- */
-                /* Write errors if any   */
-                /* --------------------- */
-                if failed_transactions.len() > 0 {
-                    eprintln!("Some transactions could not be handled. See output below:");
-                    failed_transactions
-                        .iter()
-                        .for_each(|e| eprintln!("- {:?}", e));
-                    process::exit(1)
-                } else {
-                    process::exit(0)
+use config::{Input, Record, Store};
+
+fn read(filepath: &str, input: Input) -> Result<Vec<transaction::Transaction>, error::Csv> {
+    match input {
+        Input::Csv => reader::read_file_from_path(filepath),
+        Input::Mapped => mmap_reader::read_file_from_path(filepath),
+        Input::Parallel(threads) => parallel_reader::read_file_from_path(filepath, threads),
+    }
+}
+
+fn process(filepath: &str, run: config::Run) -> Result<ledger::Outcome, error::Csv> {
+    let config::Run {
+        input,
+        shards,
+        store,
+        reorder,
+        record,
+        ledger: config,
+        ..
+    } = run;
+    match record {
+        Some(Record::Events(log)) => return apply_with_events(filepath, input, &log, config),
+        Some(Record::Journal(path)) => return apply_with_journal(filepath, input, &path, config),
+        Some(Record::Invariants) => return apply_with_checker(filepath, input, config),
+        None => (),
+    }
+    if let Store::Snapshot(path, Some(log)) = store {
+        return apply_durably(filepath, &path, &log, config);
+    }
+    if let Store::Snapshot(path, None) = store {
+        let snapshot = snapshot::read(&path)
+            .map_err(|e| {
+                error::Csv::FileReadError(format!("Error reading snapshot {}: {:?}", path, e))
+            })?
+            .unwrap_or_default();
+        let mut ledger = ledger::Ledger::from_state(config, snapshot.state);
+        let mut input = snapshot.input;
+        let mut errors = vec![];
+        for (transaction, checkpoint) in remaining(filepath, input.as_ref())? {
+            if let Err(e) = ledger.apply(&transaction) {
+                errors.push(e);
+            }
+            input = Some(checkpoint);
+        }
+        snapshot::write(
+            &path,
+            &snapshot::Snapshot {
+                state: ledger.state(),
+                input,
+            },
+        )
+        .map_err(|e| {
+            error::Csv::FileReadError(format!("Error writing snapshot {}: {:?}", path, e))
+        })?;
+        return Ok(ledger::Outcome {
+            accounts: ledger.accounts(),
+            warnings: ledger.take_warnings(),
+            errors,
+        });
+    }
+    if let Some(shards) = shards {
+        let transactions = read(filepath, input)?;
+        if let Some(shared) = sharded_ledger::shared_tx_id(&transactions) {
+            return Err(error::Csv::ParseError(vec![format!(
+                "--shards needs deposits of different clients to have different tx ids: {:?}",
+                shared
+            )]));
+        }
+        return Ok(sharded_ledger::parse_transactions(
+            &transactions,
+            shards,
+            config,
+        ));
+    }
+
+    match store {
+        Store::Memory => reorder_and_apply(filepath, input, reorder, ledger::Ledger::new(config)),
+        Store::Snapshot(..) => unreachable!("handled above"),
+        Store::Disk(path, memory_budget) => {
+            let deposits = disk_store::DiskStore::create(&path, memory_budget).map_err(|e| {
+                error::Csv::FileReadError(format!("Error creating {}: {:?}", path, e))
+            })?;
+            reorder_and_apply(
+                filepath,
+                input,
+                reorder,
+                ledger::Ledger::with_store(config, deposits),
+            )
+        }
+    }
+}
+
+/*
+ * The rows of the input the ledger hasn't seen yet, going by the checkpoint it
+ * was saved with.
+ * */
+fn remaining(
+    filepath: &str,
+    from: Option<&checkpoint::Checkpoint>,
+) -> Result<Vec<(transaction::Transaction, checkpoint::Checkpoint)>, error::Csv> {
+    let remaining = checkpoint::read_remaining(filepath, from)?;
+    if remaining.skipped > 0 {
+        eprintln!(
+            "Skipping the first {} rows of {}, they were applied before",
+            remaining.skipped, filepath
+        );
+    }
+    if remaining.unfinished > 0 {
+        eprintln!(
+            "The last line of {} doesn't end in a newline yet, leaving it for the next run",
+            filepath
+        );
+    }
+    Ok(remaining.transactions)
+}
+
+/*
+ * Picks up from the snapshot and whatever the log has after it, and continues
+ * with the rows of the input after the last one in there. Their errors went
+ * wherever the run that applied them sent them.
+ * */
+fn apply_durably(
+    filepath: &str,
+    path: &str,
+    log: &str,
+    config: ledger::Config,
+) -> Result<ledger::Outcome, error::Csv> {
+    let failed = |e: std::io::Error| {
+        error::Csv::FileReadError(format!("Error with write-ahead log {}: {:?}", log, e))
+    };
+    let mut ledger = wal::DurableLedger::open(path, log, config).map_err(failed)?;
+    if ledger.replayed() > 0 {
+        eprintln!("Recovered {} transactions from {}", ledger.replayed(), log);
+    }
+    let mut errors = vec![];
+    for (transaction, checkpoint) in remaining(filepath, ledger.input())? {
+        if let Err(e) = ledger
+            .apply(&transaction, Some(checkpoint))
+            .map_err(failed)?
+        {
+            errors.push(e);
+        }
+        if ledger.position() % wal::SYNC_EVERY == 0 {
+            ledger.sync().map_err(failed)?;
+        }
+    }
+    ledger.sync().map_err(failed)?;
+    ledger.checkpoint().map_err(failed)?;
+    Ok(ledger::Outcome {
+        accounts: ledger.ledger().accounts(),
+        warnings: ledger.take_warnings(),
+        errors,
+    })
+}
+
+/*
+ * Appends what happened to every account to the log at `log`, once the whole
+ * input went through.
+ * */
+fn apply_with_events(
+    filepath: &str,
+    input: Input,
+    log: &str,
+    config: ledger::Config,
+) -> Result<ledger::Outcome, error::Csv> {
+    let written = |e: std::io::Error| {
+        error::Csv::FileReadError(format!("Error writing events to {}: {:?}", log, e))
+    };
+    events::truncate(log).map_err(written)?;
+    let mut ledger = ledger::Ledger::new(config);
+    let mut errors = vec![];
+    let mut happened = vec![];
+    for transaction in read(filepath, input)? {
+        let (result, mut applied) = events::apply(&mut ledger, &transaction);
+        if let Err(e) = result {
+            errors.push(e);
+        }
+        happened.append(&mut applied);
+    }
+    events::append(log, &happened).map_err(written)?;
+    Ok(ledger::Outcome {
+        accounts: ledger.accounts(),
+        warnings: ledger.take_warnings(),
+        errors,
+    })
+}
+
+/*
+ * Writes the trial balance to `path` once the whole input went through.
+ * */
+fn apply_with_journal(
+    filepath: &str,
+    input: Input,
+    path: &str,
+    config: ledger::Config,
+) -> Result<ledger::Outcome, error::Csv> {
+    let mut ledger = journal::JournalingLedger::new(ledger::Ledger::new(config));
+    let errors = read(filepath, input)?
+        .iter()
+        .filter_map(|transaction| ledger.apply(transaction).err())
+        .collect();
+
+    let mut written = vec![];
+    journal::write_trial_balance(&ledger.journal().trial_balance(), &mut written)
+        .map_err(|e| error::Csv::FileReadError(format!("Error writing trial balance: {:?}", e)))?;
+    follow::write_atomically(path, &written).map_err(|e| {
+        error::Csv::FileReadError(format!("Error writing trial balance to {}: {:?}", path, e))
+    })?;
+    Ok(ledger::Outcome {
+        accounts: ledger.accounts(),
+        warnings: ledger.take_warnings(),
+        errors,
+    })
+}
+
+/*
+ * Lists every violation on stderr as it's found, and once more whatever a
+ * check from scratch turns up at the end.
+ * */
+fn apply_with_checker(
+    filepath: &str,
+    input: Input,
+    config: ledger::Config,
+) -> Result<ledger::Outcome, error::Csv> {
+    let mut ledger = ledger::Ledger::new(config);
+    let mut checker = invariants::Checker::new();
+    let mut errors = vec![];
+    for transaction in read(filepath, input)? {
+        if let Err(e) = checker.apply(&mut ledger, &transaction) {
+            errors.push(e);
+        }
+        checker
+            .take_violations()
+            .iter()
+            .for_each(|violation| eprintln!("Invariant violated: {:?}", violation));
+    }
+    checker
+        .check(&ledger)
+        .iter()
+        .for_each(|violation| eprintln!("Invariant violated: {:?}", violation));
+    Ok(ledger::Outcome {
+        accounts: ledger.accounts(),
+        warnings: ledger.take_warnings(),
+        errors,
+    })
+}
+
+fn reorder_and_apply<S: deposit::DepositStore>(
+    filepath: &str,
+    input: Input,
+    reorder: Option<reorder::Config>,
+    mut ledger: ledger::Ledger<S>,
+) -> Result<ledger::Outcome, error::Csv> {
+    if let Some(reorder) = reorder {
+        let mut ledger = reorder::ReorderingLedger::new(ledger, reorder);
+        read(filepath, input)?
+            .iter()
+            .for_each(|transaction| ledger.apply(transaction));
+        let (outcome, changed) = ledger.finish();
+        if !changed.is_empty() {
+            eprintln!("Late transactions changed how these went:");
+            changed.iter().for_each(|c| eprintln!("- {:?}", c));
+        }
+        return Ok(outcome);
+    }
+    apply(filepath, input, &mut ledger)
+}
+
+fn apply<S: deposit::DepositStore>(
+    filepath: &str,
+    input: Input,
+    ledger: &mut ledger::Ledger<S>,
+) -> Result<ledger::Outcome, error::Csv> {
+    let errors = match input {
+        Input::Parallel(threads) => {
+            parallel_reader::apply_file_from_path(filepath, threads, ledger)?
+        }
+        input => read(filepath, input)?
+            .iter()
+            .filter_map(|transaction| ledger.apply(transaction).err())
+            .collect(),
+    };
+    Ok(ledger::Outcome {
+        accounts: ledger.accounts(),
+        warnings: ledger.take_warnings(),
+        errors,
+    })
+}
+
+fn run(filepath: &str, run: config::Run) {
+    let (columns, written) = (writer::columns(&run.ledger), run.ledger.clone());
+    let review = run.review.clone();
+    match process(filepath, run) {
+        Ok(ledger::Outcome {
+            accounts: parsed_transactions,
+            errors: failed_transactions,
+            warnings,
+        }) => {
+            /* --------------------- */
+            /* Write correct results */
+            /* --------------------- */
+            let mut std_output = vec![];
+            /*
+             * I'm ignoring the error's here, rely'ing on the soundness of my types,
+             * ideally, this should be handled correctly.
+             * */
+            let _ =
+                writer::write_accounts(&parsed_transactions, &columns, &written, &mut std_output);
+            let _ = io::stdout().write_all(&std_output);
+
+            /* --------------------- */
+            /* Write flagged if any  */
+            /* --------------------- */
+            let (flagged, warnings): (Vec<_>, Vec<_>) = warnings
+                .into_iter()
+                .partition(|w| matches!(w, error::TransactionError::Flagged(..)));
+            match &review {
+                Some(path) => {
+                    if let Err(e) = std::fs::File::create(path)
+                        .map_err(csv::Error::from)
+                        .and_then(|file| writer::write_review(&flagged, file))
+                    {
+                        eprintln!("Failed to write {}: {:?}", path, e);
+                    }
+                }
+                None if !flagged.is_empty() => {
+                    eprintln!("Some transactions were flagged for review:");
+                    flagged.iter().for_each(|f| eprintln!("- {:?}", f));
+                }
+                None => (),
+            }
+
+            /* --------------------- */
+            /* Write warnings if any */
+            /* --------------------- */
+            if !warnings.is_empty() {
+                eprintln!("Some transactions were applied, but look suspicious:");
+                warnings.iter().for_each(|w| eprintln!("- {:?}", w));
+            }
+
+            /* --------------------- */
+            /* Write errors if any   */
+            /* --------------------- */
+            if !failed_transactions.is_empty() {
+                eprintln!("Some transactions could not be handled. See output below:");
+                failed_transactions
+                    .iter()
+                    .for_each(|e| eprintln!("- {:?}", e));
+                process::exit(1)
+            } else {
+                process::exit(0)
+            }
+        }
+        Err(errors) => {
+            eprintln!("Failed to parse CSV input");
+            eprintln!("{:?}", errors);
+        }
+    }
+}
+
+/*
+ * Only reads the file, nothing gets applied. Exits non-zero if anything looks off.
+ * */
+fn validate(filepath: &str) {
+    match validation::validate_file_from_path(filepath) {
+        Ok(problems) => {
+            if problems.is_empty() {
+                eprintln!("No problems found");
+                process::exit(0)
+            }
+            problems.iter().for_each(|p| eprintln!("- {:?}", p));
+            eprintln!("Found {} problem(s):", problems.len());
+            validation::summarize(&problems)
+                .iter()
+                .filter(|(_, count)| *count > 0)
+                .for_each(|(kind, count)| eprintln!("  {}: {}", kind, count));
+            process::exit(1)
+        }
+        Err(errors) => {
+            eprintln!("Failed to read CSV input");
+            eprintln!("{:?}", errors);
+            process::exit(1)
+        }
+    }
+}
+
+/*
+ * Rebuilds the accounts from nothing but the events, and checks they're the
+ * same as what applying the input gives. Exits non-zero if they're not.
+ * */
+fn verify(filepath: &str, log: &str, config: ledger::Config) {
+    let (direct, _) = reader::read_file_from_path(filepath)
+        .map(|transactions| ledger::parse_transactions_with_config(&transactions, config.clone()))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to parse CSV input");
+            eprintln!("{:?}", e);
+            process::exit(1)
+        });
+    let happened = events::read(log).unwrap_or_else(|e| {
+        eprintln!("Failed to read events from {}", log);
+        eprintln!("{:?}", e);
+        process::exit(1)
+    });
+    let rebuilt = events::rebuild(&happened, &config.clients).unwrap_or_else(|e| {
+        eprintln!("Failed to rebuild the accounts: {}", e);
+        process::exit(1)
+    });
+
+    if rebuilt == direct {
+        eprintln!(
+            "{} accounts rebuilt from {} events match the input",
+            rebuilt.len(),
+            happened.len()
+        );
+        process::exit(0)
+    }
+    eprintln!("The accounts rebuilt from the events don't match the input:");
+    let mut clients = rebuilt
+        .iter()
+        .chain(direct.iter())
+        .map(|account| account.client())
+        .collect::<Vec<u16>>();
+    clients.sort_unstable();
+    clients.dedup();
+    clients.iter().for_each(|client| {
+        let find = |accounts: &[account::Account]| {
+            accounts
+                .iter()
+                .find(|account| account.client() == *client)
+                .copied()
+        };
+        let (rebuilt, direct) = (find(&rebuilt), find(&direct));
+        if rebuilt != direct {
+            eprintln!("- rebuilt {:?}, input {:?}", rebuilt, direct);
+        }
+    });
+    process::exit(1)
+}
+
+/*
+ * Writes the client's account as of that point, then a blank line, then the
+ * transactions that got it there.
+ * */
+fn balance(filepath: &str, client: u16, as_of: query::AsOf, config: ledger::Config) {
+    let transactions = reader::read_file_from_path(filepath).unwrap_or_else(|e| {
+        eprintln!("Failed to parse CSV input");
+        eprintln!("{:?}", e);
+        process::exit(1)
+    });
+    match query::replay(&transactions, client, as_of, config) {
+        Some(query::Balance {
+            account,
+            transactions,
+        }) => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            match account {
+                Some(account) => {
+                    let _ = writer.serialize(account);
                 }
+                None => eprintln!("Client {} had no account yet", client),
+            }
+            let mut output = writer.into_inner().unwrap_or_default();
+            output.push(b'\n');
+            let mut writer = csv::Writer::from_writer(output);
+            transactions.iter().for_each(|transaction| {
+                let _ = writer.serialize(transaction);
+            });
+            let _ = io::stdout().write_all(&writer.into_inner().unwrap_or_default());
+        }
+        None => {
+            eprintln!("There's no deposit or withdrawal with that tx id");
+            process::exit(1)
+        }
+    }
+}
+
+/*
+ * Never returns. Keeps polling the file, and writes a new snapshot of the
+ * accounts to `output` whenever something changed.
+ * */
+fn follow(filepath: &str, output: &str, interval: Duration, config: ledger::Config) {
+    let mut follower = follow::Follower::resume(filepath, output, config).unwrap_or_else(|e| {
+        eprintln!("Failed to start following {}", filepath);
+        eprintln!("{:?}", e);
+        process::exit(1)
+    });
+    if follower.offset() > 0 {
+        eprintln!("Resuming {} from byte {}", filepath, follower.offset());
+    }
+
+    loop {
+        match follower.poll() {
+            Ok(batch) => {
+                batch.malformed.iter().for_each(|e| eprintln!("- {}", e));
+                batch.errors.iter().for_each(|e| eprintln!("- {:?}", e));
+                if !batch.warnings.is_empty() {
+                    eprintln!("Some transactions were applied, but look suspicious:");
+                    batch.warnings.iter().for_each(|w| eprintln!("- {:?}", w));
+                }
+                if batch.rows > 0 {
+                    if let Err(e) = follower.write_snapshot(output) {
+                        eprintln!("Failed to write snapshot to {}: {:?}", output, e);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to read {}", filepath);
+                eprintln!("{:?}", e);
+                process::exit(1)
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let (flags, positional): (Vec<&String>, Vec<&String>) =
+        args.iter().partition(|arg| arg.starts_with("--"));
+    let config = config::ledger(&flags).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2)
+    });
+
+    match (positional.first().map(|x| x.as_str()), positional.get(1)) {
+        (Some("validate"), Some(filepath)) => validate(filepath),
+        (Some("validate"), None) => eprintln!("Please provide a path to the CSV file to validate"),
+        (Some("verify"), Some(filepath)) => match config::flag(&flags, "events") {
+            Some(log) if !log.is_empty() => verify(filepath, log, config),
+            _ => eprintln!("Please provide the events to verify with --events=<path>"),
+        },
+        (Some("verify"), None) => eprintln!("Please provide a path to the CSV file to verify"),
+        (Some("follow"), Some(filepath)) => match config::flag(&flags, "output") {
+            Some(output) if !output.is_empty() => {
+                let interval = config::flag(&flags, "interval")
+                    .map_or(Ok(1000), str::parse::<u64>)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Invalid interval: {:?}", e);
+                        process::exit(2)
+                    });
+                follow(filepath, output, Duration::from_millis(interval), config)
+            }
+            _ => eprintln!("Please provide a path to write the accounts to with --output=<path>"),
+        },
+        (Some("follow"), None) => eprintln!("Please provide a path to the CSV file to follow"),
+        (Some("balance"), Some(filepath)) => {
+            let client = config::flag(&flags, "client").map(str::parse::<u16>);
+            let as_of = match (config::flag(&flags, "tx"), config::flag(&flags, "at")) {
+                (Some(tx), None) => tx
+                    .parse::<u32>()
+                    .map(query::AsOf::Tx)
+                    .map_err(|e| format!("{:?}", e)),
+                (None, Some(at)) => timestamp::parse(at).map(query::AsOf::Time),
+                _ => Err("pass either --tx=<tx id> or --at=<timestamp>".to_string()),
+            };
+            match (client, as_of) {
+                (Some(Ok(client)), Ok(as_of)) => balance(filepath, client, as_of, config),
+                (None, _) => eprintln!("Please provide a client with --client=<id>"),
+                (Some(Err(e)), _) => eprintln!("Invalid client: {:?}", e),
+                (_, Err(e)) => eprintln!("Invalid point in time: {}", e),
             }
-            Err(errors) => {
-                eprintln!("Failed to parse CSV input");
-                eprintln!("{:?}", errors);
+        }
+        (Some("balance"), None) => eprintln!("Please provide a path to the CSV file to query"),
+        (Some(filepath), _) => match config::run(&flags, config) {
+            Ok(options) => run(filepath, options),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2)
             }
         },
-    )
+        (None, _) => eprintln!("Please provide a path to the CSV file with transactions"),
+    }
 }
@@ -0,0 +1,253 @@
+use crate::currency::{self, Currency};
+use crate::error::Csv;
+use crate::timestamp;
+use crate::transaction::{Transaction, TransactionType};
+use csv_core::{ReadRecordResult, Reader};
+use memmap2::MmapOptions;
+use std::fs::File;
+use std::str;
+
+/*
+ * A reader for large local files. Instead of going through serde, which hands
+ * out a String per field, we memory-map the file and parse every row straight
+ * from the mapped bytes. csv_core does the actual CSV work (quoting, line
+ * endings, BOM), so we're splitting fields exactly like `csv::Reader` does. The
+ * only allocations are the two scratch buffers below, which are reused for
+ * every row, and the resulting Vec.
+ *
+ * The output must be identical to `reader::read_file_from_path`, so the field
+ * parsing below mirrors what the csv deserializer does for our types.
+ * */
+pub fn read_file_from_path(path: &str) -> Result<Vec<Transaction>, Csv> {
+    with_mapped_file(path, parse_bytes)
+}
+
+pub fn with_mapped_file<T, F>(path: &str, f: F) -> Result<T, Csv>
+where
+    F: FnOnce(&[u8]) -> Result<T, Csv>,
+{
+    let file =
+        File::open(path).map_err(|e| Csv::FileReadError(format!("Error reading file: {:?}", e)))?;
+    let length = file
+        .metadata()
+        .map_err(|e| Csv::FileReadError(format!("Error reading file: {:?}", e)))?
+        .len();
+    if length == 0 {
+        return f(&[]);
+    }
+
+    /*
+     * Safety: the mapping is only valid as long as nobody truncates the file
+     * underneath us. That's the price of not copying it.
+     * */
+    let mapped = unsafe { MmapOptions::new().populate().map(&file) }
+        .map_err(|e| Csv::FileReadError(format!("Error mapping file: {:?}", e)))?;
+    f(&mapped)
+}
+
+/*
+ * A single row, borrowed from the scratch buffers of `for_each_record`.
+ * */
+pub struct Record<'a> {
+    bytes: &'a [u8],
+    ends: &'a [usize],
+}
+
+impl<'a> Record<'a> {
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> &'a [u8] {
+        let start = if index == 0 { 0 } else { self.ends[index - 1] };
+        &self.bytes[start..self.ends[index]]
+    }
+}
+
+/*
+ * Where to find each field we care about in a row.
+ * */
+#[derive(Debug, Clone, Copy)]
+pub struct Columns {
+    tx_type: usize,
+    client: usize,
+    tx: usize,
+    amount: usize,
+    timestamp: Option<usize>,
+    count: usize,
+}
+
+impl Columns {
+    pub fn from_header(header: &Record) -> Result<Columns, String> {
+        let find = |name: &str| {
+            (0..header.len())
+                .position(|index| header.get(index) == name.as_bytes())
+                .ok_or(format!("missing field `{}`", name))
+        };
+        Ok(Columns {
+            tx_type: find("type")?,
+            client: find("client")?,
+            tx: find("tx")?,
+            amount: find("amount")?,
+            timestamp: find("timestamp").ok(),
+            count: header.len(),
+        })
+    }
+}
+
+/*
+ * Integers may come in as hex, the csv deserializer accepts `0x` prefixes.
+ * */
+fn parse_u16(field: &str) -> Result<u16, String> {
+    match field.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => field.parse(),
+    }
+    .map_err(|e| format!("{:?}", e))
+}
+
+fn parse_u32(field: &str) -> Result<u32, String> {
+    match field.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => field.parse(),
+    }
+    .map_err(|e| format!("{:?}", e))
+}
+
+fn parse_type(field: &str) -> Result<TransactionType, String> {
+    match field {
+        "deposit" => Ok(TransactionType::Deposit),
+        "withdrawal" => Ok(TransactionType::Withdrawal),
+        "dispute" => Ok(TransactionType::Dispute),
+        "resolve" => Ok(TransactionType::Resolve),
+        "chargeback" => Ok(TransactionType::Chargeback),
+        other => Err(format!("unknown variant `{}`", other)),
+    }
+}
+
+/* Same as `currency::from_float_string` */
+fn parse_amount(field: &str) -> Result<Currency, String> {
+    match field {
+        "" => Ok(Currency(0)),
+        s => currency::parse(s),
+    }
+}
+
+pub fn parse_record(record: &Record, columns: &Columns) -> Result<Transaction, String> {
+    if record.len() != columns.count {
+        return Err(format!(
+            "found record with {} fields, but the previous record has {} fields",
+            record.len(),
+            columns.count
+        ));
+    }
+    let start = |index: usize| {
+        if index == 0 {
+            0
+        } else {
+            record.ends[index - 1]
+        }
+    };
+    /*
+     * The delimiters are gone, so a character can be split across two fields
+     * and still be fine as a whole. Every field is checked on its own, unless
+     * it's all ASCII, which is what rows mostly are. Valid fields put together
+     * are valid as a whole, and every field ends on a character.
+     * */
+    if !record.bytes.is_ascii() {
+        (0..record.len()).try_for_each(|index| {
+            str::from_utf8(&record.bytes[start(index)..record.ends[index]])
+                .map(|_| ())
+                .map_err(|e| format!("invalid utf-8 in field {}: {:?}", index, e))
+        })?;
+    }
+    let text = str::from_utf8(record.bytes).map_err(|e| format!("invalid utf-8: {:?}", e))?;
+    let field = |index: usize| &text[start(index)..record.ends[index]];
+    Ok(Transaction {
+        tx_type: parse_type(field(columns.tx_type))?,
+        client: parse_u16(field(columns.client))?,
+        tx: parse_u32(field(columns.tx))?,
+        amount: parse_amount(field(columns.amount))?,
+        timestamp: match columns.timestamp.map(field) {
+            None | Some("") => None,
+            Some(text) => Some(timestamp::parse(text)?),
+        },
+    })
+}
+
+/*
+ * Calls `f` for every record in `bytes`, header included. Stops at the first
+ * error `f` returns.
+ * */
+pub fn for_each_record<F>(bytes: &[u8], mut f: F) -> Result<(), String>
+where
+    F: FnMut(&Record) -> Result<(), String>,
+{
+    let mut reader = Reader::new();
+    let mut input = bytes;
+    let mut output = vec![0; 1024];
+    let mut ends = vec![0; 16];
+
+    loop {
+        let (mut outlen, mut endlen) = (0, 0);
+        loop {
+            let (result, nin, nout, nend) =
+                reader.read_record(input, &mut output[outlen..], &mut ends[endlen..]);
+            input = &input[nin..];
+            outlen += nout;
+            endlen += nend;
+            match result {
+                ReadRecordResult::InputEmpty => continue,
+                ReadRecordResult::OutputFull => output.resize(output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => ends.resize(ends.len() * 2, 0),
+                ReadRecordResult::Record => break,
+                ReadRecordResult::End => return Ok(()),
+            }
+        }
+
+        f(&Record {
+            bytes: &output[..outlen],
+            ends: &ends[..endlen],
+        })?;
+    }
+}
+
+/*
+ * Like `reader::read_file_from_path`, a single malformed row discards the
+ * whole input.
+ * */
+pub fn parse_bytes(bytes: &[u8]) -> Result<Vec<Transaction>, Csv> {
+    let mut columns: Option<Result<Columns, String>> = None;
+    let mut results = vec![];
+    let mut errors = vec![];
+    let mut row = 0;
+
+    for_each_record(bytes, |record| {
+        match &columns {
+            None => columns = Some(Columns::from_header(record)),
+            Some(columns) => {
+                row += 1;
+                match columns
+                    .as_ref()
+                    .map_err(String::clone)
+                    .and_then(|columns| parse_record(record, columns))
+                {
+                    Ok(transaction) => results.push(transaction),
+                    Err(e) => errors.push(format!("row {}: {}", row, e)),
+                }
+            }
+        }
+        Ok(())
+    })
+    .map_err(|e| Csv::ParseError(vec![e]))?;
+
+    if !errors.is_empty() {
+        Err(Csv::ParseError(errors))
+    } else {
+        Ok(results)
+    }
+}
@@ -0,0 +1,191 @@
+use crate::deposit::DepositStore;
+use crate::error::{Csv, TransactionError};
+use crate::ledger::Ledger;
+use crate::mmap_reader::{self, Columns};
+use crate::transaction::Transaction;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/*
+ * Parsing rows doesn't depend on anything that came before, applying them to
+ * the ledger does. So we split the input into chunks at line boundaries, parse
+ * those on a handful of threads, and hand them off in the original order on the
+ * calling thread. The ledger never knows the difference.
+ *
+ * Chunks are split on newlines, so a quoted field with a newline in it would
+ * end up in two chunks. None of our columns have a reason to contain one.
+ * */
+
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/*
+ * Splits `bytes` into chunks of roughly `size` bytes, every one of them ending
+ * right after a newline (or at the end of the input).
+ * */
+pub fn split_lines(bytes: &[u8], size: usize) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let end = if rest.len() <= size {
+            rest.len()
+        } else {
+            rest[size..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(rest.len(), |newline| size + newline + 1)
+        };
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    chunks
+}
+
+fn parse_chunk(
+    offset: usize,
+    chunk: &[u8],
+    columns: &Columns,
+) -> Result<Vec<Transaction>, Vec<String>> {
+    let mut results = vec![];
+    let mut errors = vec![];
+    let mut row = 0;
+    let outcome = mmap_reader::for_each_record(chunk, |record| {
+        row += 1;
+        match mmap_reader::parse_record(record, columns) {
+            Ok(transaction) => results.push(transaction),
+            Err(e) => errors.push(format!("byte {}, row {}: {}", offset, row, e)),
+        }
+        Ok(())
+    });
+    if let Err(e) = outcome {
+        errors.push(e);
+    }
+
+    if errors.is_empty() {
+        Ok(results)
+    } else {
+        Err(errors)
+    }
+}
+
+/*
+ * Calls `f` with the transactions of every chunk, in file order, while the
+ * chunks after it are still being parsed. Once a chunk turns out to have a
+ * malformed row, nothing after it is handed off anymore, and all errors are
+ * returned. Whatever was handed off before that stays handed off.
+ * */
+pub fn for_each_chunk<F>(bytes: &[u8], threads: usize, mut f: F) -> Result<(), Csv>
+where
+    F: FnMut(Vec<Transaction>),
+{
+    let header_end = bytes
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(bytes.len(), |newline| newline + 1);
+    let mut columns = None;
+    mmap_reader::for_each_record(&bytes[..header_end], |record| {
+        columns = Some(Columns::from_header(record));
+        Ok(())
+    })
+    .map_err(|e| Csv::ParseError(vec![e]))?;
+    let columns = match columns {
+        None => return Ok(()),
+        Some(columns) => columns.map_err(|e| Csv::ParseError(vec![e]))?,
+    };
+
+    let body = &bytes[header_end..];
+    let threads = threads.max(1);
+    let size = (body.len() / (threads * 4)).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+    let chunks = split_lines(body, size);
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        (0..threads.min(chunks.len())).for_each(|_| {
+            let sender = sender.clone();
+            let (chunks, next, columns) = (&chunks, &next, &columns);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                match chunks.get(index) {
+                    Some(chunk) => {
+                        let offset = chunk.as_ptr() as usize - bytes.as_ptr() as usize;
+                        if sender
+                            .send((index, parse_chunk(offset, chunk, columns)))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            });
+        });
+        drop(sender);
+
+        /*
+         * Chunks come in whenever a thread is done with them. The ones that
+         * are early wait here until everything before them has been handed off.
+         * */
+        let mut waiting = BTreeMap::new();
+        let mut expected = 0;
+        let mut errors = vec![];
+        for (index, result) in receiver {
+            waiting.insert(index, result);
+            while let Some(result) = waiting.remove(&expected) {
+                match result {
+                    Ok(transactions) if errors.is_empty() => f(transactions),
+                    Ok(_) => (),
+                    Err(mut e) => errors.append(&mut e),
+                }
+                expected += 1;
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Csv::ParseError(errors))
+        }
+    })
+}
+
+/*
+ * Same result as `reader::read_file_from_path`, just on more than one thread.
+ * */
+pub fn read_file_from_path(path: &str, threads: usize) -> Result<Vec<Transaction>, Csv> {
+    let mut results = vec![];
+    mmap_reader::with_mapped_file(path, |bytes| {
+        for_each_chunk(bytes, threads, |mut transactions| {
+            results.append(&mut transactions)
+        })
+    })?;
+    Ok(results)
+}
+
+/*
+ * Streams the file straight into `ledger`, applying every chunk as soon as
+ * it's its turn. Returns the transactions the ledger refused.
+ * */
+pub fn apply_file_from_path<S: DepositStore>(
+    path: &str,
+    threads: usize,
+    ledger: &mut Ledger<S>,
+) -> Result<Vec<TransactionError>, Csv> {
+    let mut transaction_errors = vec![];
+    mmap_reader::with_mapped_file(path, |bytes| {
+        for_each_chunk(bytes, threads, |transactions| {
+            transactions.iter().for_each(|transaction| {
+                if let Err(e) = ledger.apply(transaction) {
+                    transaction_errors.push(e)
+                }
+            })
+        })
+    })?;
+    Ok(transaction_errors)
+}
@@ -0,0 +1,113 @@
+use crate::account::Account;
+use crate::history::History;
+use crate::ledger::{Config, Ledger};
+use crate::timestamp::Timestamp;
+use crate::transaction::{Transaction, TransactionType};
+
+/*
+ * What a client's account looked like at some point in the past, and the
+ * transactions of that client that went through up to then, in the order they
+ * were applied in.
+ *
+ * A point is either right after the deposit or withdrawal with a given tx id,
+ * which doesn't have to be one of the client's own, or a moment in time.
+ * Rows without a timestamp happened at the latest timestamp before them.
+ * */
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AsOf {
+    Tx(u32),
+    Time(Timestamp),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Balance {
+    pub account: Option<Account>,
+    pub transactions: Vec<Transaction>,
+}
+
+fn is_tx(transaction: &Transaction, tx: u32) -> bool {
+    transaction.tx == tx
+        && matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        )
+}
+
+/*
+ * Applies the input from the start, and stops at the first row past the
+ * point. That's what the ledger would have shown at that moment, even if rows
+ * further down are out of order and belong before it. None if the tx id never
+ * shows up.
+ * */
+pub fn replay(
+    transactions: &[Transaction],
+    client: u16,
+    as_of: AsOf,
+    config: Config,
+) -> Option<Balance> {
+    let mut ledger = Ledger::new(config);
+    let mut latest = None;
+    let mut contributing = vec![];
+    for transaction in transactions {
+        latest = latest.max(transaction.timestamp);
+        if let AsOf::Time(time) = as_of {
+            if transaction.timestamp.or(latest) > Some(time) {
+                break;
+            }
+        }
+        if ledger.apply(transaction).is_ok() && transaction.client == client {
+            contributing.push(*transaction);
+        }
+        ledger.take_warnings();
+        if let AsOf::Tx(tx) = as_of {
+            if is_tx(transaction, tx) {
+                return Some(Balance {
+                    account: ledger.account(client),
+                    transactions: contributing,
+                });
+            }
+        }
+    }
+    match as_of {
+        AsOf::Tx(_) => None,
+        AsOf::Time(_) => Some(Balance {
+            account: ledger.account(client),
+            transactions: contributing,
+        }),
+    }
+}
+
+/*
+ * Same, from a history that's already there. A history only knows about its
+ * own client, so the tx id has to be one of theirs.
+ * */
+pub fn from_history(history: &History, client: u16, as_of: AsOf) -> Option<Balance> {
+    let versions = history.versions(client);
+    let until = match as_of {
+        AsOf::Tx(tx) => {
+            versions
+                .iter()
+                .position(|version| is_tx(&version.transaction, tx))?
+                + 1
+        }
+        AsOf::Time(time) => {
+            let mut latest = None;
+            versions
+                .iter()
+                .take_while(|version| {
+                    latest = latest.max(version.transaction.timestamp);
+                    version.transaction.timestamp.or(latest) <= Some(time)
+                })
+                .count()
+        }
+    };
+    Some(Balance {
+        account: versions[..until].last().and_then(|version| version.account),
+        transactions: versions[..until]
+            .iter()
+            .filter(|version| version.error.is_none())
+            .map(|version| version.transaction)
+            .collect(),
+    })
+}
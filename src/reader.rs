@@ -18,7 +18,7 @@ pub fn read_file_from_path(path: &str) -> Result<Vec<transaction::Transaction>,
                 Err(e) => errors.push(e),
             });
 
-            if errors.len() > 0 {
+            if !errors.is_empty() {
                 Err(Csv::ParseError(
                     errors
                         .iter()
@@ -30,3 +30,53 @@ pub fn read_file_from_path(path: &str) -> Result<Vec<transaction::Transaction>,
             }
         })
 }
+
+/*
+ * A single row of the input, as seen by the reader. Unlike the function above,
+ * this keeps malformed rows around, so they can be reported on one by one.
+ * `has_amount` tells wether the amount column had anything in it, as the
+ * deserializer happily turns an empty amount into 0.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub struct Row {
+    pub line: u64,
+    pub transaction: Result<transaction::Transaction, String>,
+    pub has_amount: bool,
+}
+
+pub fn read_rows_from_path(path: &str) -> Result<Vec<Row>, Csv> {
+    csv::Reader::from_path(path)
+        .map_err(|e| Csv::FileReadError(format!("Error reading file: {:?}", e)))
+        .and_then(|mut reader| {
+            let headers = reader
+                .headers()
+                .map_err(|e| Csv::FileReadError(format!("Error reading headers: {:?}", e)))?
+                .clone();
+            let amount_column = headers.iter().position(|header| header == "amount");
+
+            Ok(reader
+                .records()
+                .enumerate()
+                .map(|(index, record)| match record {
+                    Ok(record) => Row {
+                        line: record
+                            .position()
+                            .map_or(index as u64 + 2, |position| position.line()),
+                        transaction: record
+                            .deserialize(Some(&headers))
+                            .map_err(|e| format!("{:?}", e)),
+                        has_amount: amount_column
+                            .and_then(|column| record.get(column))
+                            .is_some_and(|amount| !amount.trim().is_empty()),
+                    },
+                    Err(e) => Row {
+                        line: e
+                            .position()
+                            .map_or(index as u64 + 2, |position| position.line()),
+                        transaction: Err(format!("{:?}", e)),
+                        has_amount: false,
+                    },
+                })
+                .collect())
+        })
+}
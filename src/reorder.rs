@@ -0,0 +1,248 @@
+use crate::account::Account;
+use crate::deposit::DepositStore;
+use crate::error::TransactionError;
+use crate::history::{Changed, History};
+use crate::ledger::{Ledger, Outcome};
+use crate::transaction::{Transaction, TransactionType};
+use std::collections::BTreeMap;
+
+/*
+ * With several streams merged into one, transactions don't come in the order
+ * they happened in. The buffer below sits in front of the ledger, holds on to
+ * transactions for a while, and lets them through in order.
+ *
+ * Transactions are ordered by a key: their timestamp, or their tx id for
+ * deposits and withdrawals, which are handed out in sequence. Disputes,
+ * resolves and chargebacks don't have a sequence number of their own, and
+ * neither do rows without a timestamp. Those are keyed at the latest key seen
+ * so far, as in "this happened now".
+ *
+ * A transaction is held until a key of at least its own plus the window came
+ * in, or until there's more than `capacity` of them held. Whatever arrives
+ * with a key below what was already let through is late, and is handed back
+ * as such.
+ * */
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Order {
+    Timestamp,
+    Sequence,
+}
+
+/*
+ * What to do with a transaction that arrives after its window closed.
+ * `Append` applies it as if it were on time, out of order. `Recompute` puts it
+ * where it belongs, and applies all of its client's transactions again from
+ * the start.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum LatePolicy {
+    Reject,
+    Append,
+    Recompute,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Config {
+    pub order: Order,
+    /* In milliseconds when ordering by timestamp, in tx ids otherwise */
+    pub window: u64,
+    pub capacity: usize,
+    pub late: LatePolicy,
+}
+
+/*
+ * Every transaction comes back with its key, and a number in the order it
+ * arrived in.
+ * */
+#[derive(Debug, Eq, PartialEq)]
+pub enum Released {
+    InOrder(u64, u64, Transaction),
+    Late(u64, u64, Transaction),
+}
+
+pub struct ReorderBuffer {
+    order: Order,
+    window: u64,
+    capacity: usize,
+    /* Arrival is part of the key, so equal keys keep the order they came in */
+    held: BTreeMap<(u64, u64), Transaction>,
+    arrivals: u64,
+    latest: Option<u64>,
+    released: Option<u64>,
+}
+
+impl ReorderBuffer {
+    pub fn new(order: Order, window: u64, capacity: usize) -> ReorderBuffer {
+        ReorderBuffer {
+            order,
+            window,
+            capacity: capacity.max(1),
+            held: BTreeMap::new(),
+            arrivals: 0,
+            latest: None,
+            released: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    fn key(&self, transaction: &Transaction) -> Option<u64> {
+        match (self.order, transaction.tx_type) {
+            (Order::Timestamp, _) => transaction.timestamp.map(|timestamp| timestamp.0),
+            (Order::Sequence, TransactionType::Deposit | TransactionType::Withdrawal) => {
+                Some(transaction.tx as u64)
+            }
+            (Order::Sequence, _) => None,
+        }
+    }
+
+    fn release_first(&mut self, released: &mut Vec<Released>) {
+        if let Some(((key, arrival), transaction)) = self
+            .held
+            .keys()
+            .next()
+            .copied()
+            .and_then(|first| self.held.remove_entry(&first))
+        {
+            self.released = self.released.max(Some(key));
+            released.push(Released::InOrder(key, arrival, transaction));
+        }
+    }
+
+    /* Returns whatever can be let through now, in order */
+    pub fn push(&mut self, transaction: Transaction) -> Vec<Released> {
+        let arrival = self.arrivals;
+        self.arrivals += 1;
+        let key = self
+            .key(&transaction)
+            .or(self.latest)
+            .or(self.released)
+            .unwrap_or(0);
+
+        if Some(key) < self.released {
+            return vec![Released::Late(key, arrival, transaction)];
+        }
+        self.held.insert((key, arrival), transaction);
+        self.latest = self.latest.max(Some(key));
+
+        let mut released = vec![];
+        let closed = self
+            .latest
+            .map_or(0, |latest| latest.saturating_sub(self.window));
+        while self
+            .held
+            .keys()
+            .next()
+            .is_some_and(|(key, _)| *key <= closed)
+            || self.held.len() > self.capacity
+        {
+            self.release_first(&mut released);
+        }
+        released
+    }
+
+    /* Lets everything through that's still held, at the end of the input */
+    pub fn flush(&mut self) -> Vec<Released> {
+        let mut released = vec![];
+        while !self.held.is_empty() {
+            self.release_first(&mut released);
+        }
+        released
+    }
+}
+
+/*
+ * A ledger with a reorder buffer in front of it. Errors and warnings are in the
+ * order transactions were applied in, which isn't the order they came in.
+ *
+ * To recompute a client, every version of every account is kept in a
+ * `History`. That's not bounded in any way, so it's only done with
+ * `LatePolicy::Recompute`. Replaying a client also moves the ledger along, so
+ * a dispute window counted in transactions counts the replayed ones as well,
+ * and replayed rows look out of order to a ledger that rejects those.
+ * */
+pub struct ReorderingLedger<S: DepositStore> {
+    ledger: Ledger<S>,
+    buffer: ReorderBuffer,
+    late: LatePolicy,
+    history: History,
+    errors: Vec<TransactionError>,
+    warnings: Vec<TransactionError>,
+    changed: Vec<Changed>,
+}
+
+impl<S: DepositStore> ReorderingLedger<S> {
+    pub fn new(ledger: Ledger<S>, config: Config) -> ReorderingLedger<S> {
+        ReorderingLedger {
+            ledger,
+            buffer: ReorderBuffer::new(config.order, config.window, config.capacity),
+            late: config.late,
+            history: History::new(),
+            errors: vec![],
+            warnings: vec![],
+            changed: vec![],
+        }
+    }
+
+    fn apply_now(&mut self, key: u64, transaction: &Transaction) {
+        let result = match self.late {
+            LatePolicy::Recompute => self.history.apply(&mut self.ledger, key, transaction),
+            _ => self.ledger.apply(transaction),
+        };
+        if let Err(e) = result {
+            self.errors.push(e);
+        }
+        self.warnings.append(&mut self.ledger.take_warnings());
+    }
+
+    fn handle(&mut self, released: Vec<Released>) {
+        released.into_iter().for_each(|released| match released {
+            Released::InOrder(key, _, transaction) => self.apply_now(key, &transaction),
+            Released::Late(key, _, transaction) => match self.late {
+                LatePolicy::Reject => self.errors.push(TransactionError::Late(transaction)),
+                LatePolicy::Append => self.apply_now(key, &transaction),
+                LatePolicy::Recompute => {
+                    let mut recomputed = self.history.insert(&mut self.ledger, key, &transaction);
+                    if let Err(e) = recomputed.result {
+                        self.errors.push(e);
+                    }
+                    self.warnings.append(&mut recomputed.warnings);
+                    self.changed.append(&mut recomputed.changed);
+                }
+            },
+        })
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) {
+        let released = self.buffer.push(*transaction);
+        self.handle(released);
+    }
+
+    /*
+     * Along with the outcome, every transaction that went differently once a
+     * late one was put before it.
+     * */
+    pub fn finish(mut self) -> (Outcome, Vec<Changed>) {
+        let released = self.buffer.flush();
+        self.handle(released);
+        (
+            Outcome {
+                accounts: self.ledger.into_accounts(),
+                errors: self.errors,
+                warnings: self.warnings,
+            },
+            self.changed,
+        )
+    }
+
+    pub fn accounts(&self) -> Vec<Account> {
+        self.ledger.accounts()
+    }
+}
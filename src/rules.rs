@@ -0,0 +1,214 @@
+use crate::account::Account;
+use crate::currency::Currency;
+use crate::ledger::{Clock, DisputeWindow};
+use crate::transaction::{Transaction, TransactionType};
+use std::collections::HashMap;
+
+/*
+ * What a rule makes of a transaction. A denied one is reported as `Denied` and
+ * never applied, a flagged one is applied as usual and reported as `Flagged`,
+ * so someone can have a look at it later. The strings are the reason.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Verdict {
+    Allow,
+    Deny(String),
+    Flag(String),
+}
+
+/*
+ * Rules see every transaction before the ledger applies it, along with the
+ * account of the client it's for, if there is one yet, and where in the input
+ * it is. Whatever they need to remember they keep themselves, from what they're
+ * told went through in `applied`. Transactions that are denied, or that fail
+ * once they're applied, never get there.
+ *
+ * What a rule remembers only makes it into a snapshot as a number per client,
+ * see `remembered`. Anything else starts over when a run picks up from one.
+ * */
+pub trait Rule {
+    /* Goes next to the reason, so it's clear which rule it was */
+    fn name(&self) -> &str;
+
+    fn check(
+        &mut self,
+        transaction: &Transaction,
+        account: Option<&Account>,
+        now: Clock,
+    ) -> Verdict;
+
+    fn applied(&mut self, _transaction: &Transaction, _now: Clock) {}
+
+    /* Forget about the client, its transactions are about to be applied again */
+    fn reset(&mut self, _client: u16) {}
+
+    /*
+     * A number per client, for a snapshot, and what's handed back to a rule
+     * of the same name when a run picks up from one.
+     * */
+    fn remembered(&self) -> Vec<(u16, u64)> {
+        vec![]
+    }
+
+    fn remember(&mut self, _client: u16, _value: u64) {}
+}
+
+/* Which of the rules below to use, and how. They're all off by default. */
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Config {
+    /* Flags deposits over this */
+    pub large_deposit: Option<Currency>,
+    /* Flags withdrawals that come within this of a deposit by the same client */
+    pub rapid_withdrawal: Option<DisputeWindow>,
+    /* Flags a client's disputes after this many went through */
+    pub max_disputes: Option<u64>,
+}
+
+pub fn built_in(config: &Config) -> Vec<Box<dyn Rule>> {
+    let mut rules: Vec<Box<dyn Rule>> = vec![];
+    if let Some(threshold) = config.large_deposit {
+        rules.push(Box::new(LargeDeposit { threshold }));
+    }
+    if let Some(window) = config.rapid_withdrawal {
+        rules.push(Box::new(RapidWithdrawal {
+            window,
+            deposits: HashMap::new(),
+        }));
+    }
+    if let Some(max) = config.max_disputes {
+        rules.push(Box::new(RepeatedDisputes {
+            max,
+            disputes: HashMap::new(),
+        }));
+    }
+    rules
+}
+
+pub struct LargeDeposit {
+    pub threshold: Currency,
+}
+
+impl Rule for LargeDeposit {
+    fn name(&self) -> &str {
+        "large-deposit"
+    }
+
+    fn check(&mut self, transaction: &Transaction, _: Option<&Account>, _: Clock) -> Verdict {
+        match transaction.tx_type {
+            TransactionType::Deposit if transaction.amount.0 > self.threshold.0 => {
+                Verdict::Flag("deposit over the threshold".to_string())
+            }
+            _ => Verdict::Allow,
+        }
+    }
+}
+
+/*
+ * Money that comes in and goes right back out again. Only the latest deposit
+ * per client is remembered, that's the one any withdrawal comes closest to.
+ * With a window in time, rows without a timestamp can't be told apart, so
+ * they're left alone, deposits and withdrawals both.
+ * */
+pub struct RapidWithdrawal {
+    pub window: DisputeWindow,
+    deposits: HashMap<u16, u64>,
+}
+
+impl RapidWithdrawal {
+    fn at(&self, transaction: &Transaction, now: Clock) -> Option<u64> {
+        match (self.window, transaction.timestamp) {
+            (DisputeWindow::Time(_), None) => None,
+            _ => Some(now.at(Some(self.window))),
+        }
+    }
+}
+
+impl Rule for RapidWithdrawal {
+    fn name(&self) -> &str {
+        "rapid-withdrawal"
+    }
+
+    fn check(&mut self, transaction: &Transaction, _: Option<&Account>, now: Clock) -> Verdict {
+        let now = self.at(transaction, now);
+        match (
+            transaction.tx_type,
+            self.deposits.get(&transaction.client),
+            now,
+        ) {
+            (TransactionType::Withdrawal, Some(&at), Some(now))
+                if now < at + self.window.length() =>
+            {
+                Verdict::Flag(format!("withdrawal within {:?} of a deposit", self.window))
+            }
+            _ => Verdict::Allow,
+        }
+    }
+
+    fn applied(&mut self, transaction: &Transaction, now: Clock) {
+        if let (TransactionType::Deposit, Some(at)) =
+            (transaction.tx_type, self.at(transaction, now))
+        {
+            self.deposits.insert(transaction.client, at);
+        }
+    }
+
+    fn reset(&mut self, client: u16) {
+        self.deposits.remove(&client);
+    }
+
+    /* When every client's latest deposit was */
+    fn remembered(&self) -> Vec<(u16, u64)> {
+        self.deposits
+            .iter()
+            .map(|(client, at)| (*client, *at))
+            .collect()
+    }
+
+    fn remember(&mut self, client: u16, at: u64) {
+        self.deposits.insert(client, at);
+    }
+}
+
+/* A client that keeps disputing their deposits */
+pub struct RepeatedDisputes {
+    pub max: u64,
+    disputes: HashMap<u16, u64>,
+}
+
+impl Rule for RepeatedDisputes {
+    fn name(&self) -> &str {
+        "repeated-disputes"
+    }
+
+    fn check(&mut self, transaction: &Transaction, _: Option<&Account>, _: Clock) -> Verdict {
+        let disputes = self.disputes.get(&transaction.client).copied();
+        match transaction.tx_type {
+            TransactionType::Dispute if disputes.unwrap_or(0) >= self.max => {
+                Verdict::Flag(format!("after {} disputes", self.max))
+            }
+            _ => Verdict::Allow,
+        }
+    }
+
+    fn applied(&mut self, transaction: &Transaction, _: Clock) {
+        if transaction.tx_type == TransactionType::Dispute {
+            *self.disputes.entry(transaction.client).or_insert(0) += 1;
+        }
+    }
+
+    fn reset(&mut self, client: u16) {
+        self.disputes.remove(&client);
+    }
+
+    /* How many disputes every client made */
+    fn remembered(&self) -> Vec<(u16, u64)> {
+        self.disputes
+            .iter()
+            .map(|(client, disputes)| (*client, *disputes))
+            .collect()
+    }
+
+    fn remember(&mut self, client: u16, disputes: u64) {
+        self.disputes.insert(client, disputes);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::account::Account;
+use crate::error::TransactionError;
+use crate::ledger::{Config, Ledger, Outcome};
+use crate::timestamp::Timestamp;
+use crate::transaction::{Transaction, TransactionType};
+use crate::tx_table::TxTable;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/*
+ * Every transaction touches exactly one client, so clients never have to know
+ * about each other. We route every transaction to one of N worker threads by
+ * its client id, and each of those owns a plain `Ledger` with its own accounts,
+ * deposits and disputes. Transactions of a single client always end up at the
+ * same worker, in the order they came in, so every client sees exactly what it
+ * would've seen on a single thread.
+ *
+ * Transactions are numbered on the way in, so errors and warnings can be put
+ * back in input order once everything is merged.
+ *
+ * This does rely on deposits of different clients never sharing a tx id. A
+ * single ledger refuses the second one as a duplicate, while here it can end
+ * up at another worker that never heard of the first. Whether it's refused
+ * depends on whether the first one went through, and on how long ago that
+ * was, so there's no telling upfront. Input that has them has to go through a
+ * single ledger, see `shared_tx_id`.
+ * */
+
+const BATCH_SIZE: usize = 4096;
+const BATCHES_IN_FLIGHT: usize = 16;
+
+type Batch = Vec<(usize, Option<Timestamp>, Transaction)>;
+
+struct Shard {
+    accounts: Vec<Account>,
+    errors: Vec<(usize, TransactionError)>,
+    warnings: Vec<(usize, TransactionError)>,
+}
+
+pub struct ShardedLedger {
+    senders: Vec<SyncSender<Batch>>,
+    workers: Vec<JoinHandle<Shard>>,
+    batches: Vec<Batch>,
+    position: usize,
+    latest: Option<Timestamp>,
+}
+
+fn run_shard(config: Config, batches: mpsc::Receiver<Batch>) -> Shard {
+    let mut ledger = Ledger::new(config);
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    for batch in batches {
+        for (position, latest, transaction) in batch {
+            ledger.set_position(position as u64, latest);
+            if let Err(e) = ledger.apply(&transaction) {
+                errors.push((position, e));
+            }
+            ledger
+                .take_warnings()
+                .into_iter()
+                .for_each(|warning| warnings.push((position, warning)));
+        }
+    }
+    Shard {
+        accounts: ledger.into_accounts(),
+        errors,
+        warnings,
+    }
+}
+
+impl ShardedLedger {
+    pub fn new(shards: usize, config: Config) -> ShardedLedger {
+        let (senders, workers) = (0..shards.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel(BATCHES_IN_FLIGHT);
+                let config = config.clone();
+                (sender, thread::spawn(move || run_shard(config, receiver)))
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+        ShardedLedger {
+            batches: senders
+                .iter()
+                .map(|_| Vec::with_capacity(BATCH_SIZE))
+                .collect(),
+            senders,
+            workers,
+            position: 0,
+            latest: None,
+        }
+    }
+
+    fn send(&mut self, shard: usize) {
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+        /* Only fails if the worker panicked, which `finish` will find out about */
+        let _ = self.senders[shard].send(batch);
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) {
+        let shard = transaction.client as usize % self.senders.len();
+        self.batches[shard].push((self.position, self.latest, *transaction));
+        self.position += 1;
+        self.latest = self.latest.max(transaction.timestamp);
+        if self.batches[shard].len() == BATCH_SIZE {
+            self.send(shard);
+        }
+    }
+
+    /*
+     * Hands off whatever is left, waits for every worker, and merges their
+     * results into what a single `Ledger` would have ended up with.
+     * */
+    pub fn finish(mut self) -> Outcome {
+        (0..self.senders.len()).for_each(|shard| self.send(shard));
+        self.senders.clear();
+
+        let mut accounts = vec![];
+        let mut errors = vec![];
+        let mut warnings = vec![];
+        self.workers.into_iter().for_each(|worker| {
+            let mut shard = worker.join().expect("A ledger shard panicked");
+            accounts.append(&mut shard.accounts);
+            errors.append(&mut shard.errors);
+            warnings.append(&mut shard.warnings);
+        });
+
+        accounts.sort_by_key(|account| account.client());
+        errors.sort_by_key(|(position, _)| *position);
+        warnings.sort_by_key(|(position, _)| *position);
+        Outcome {
+            accounts,
+            errors: errors.into_iter().map(|(_, e)| e).collect(),
+            warnings: warnings.into_iter().map(|(_, w)| w).collect(),
+        }
+    }
+}
+
+/* The first deposit with a tx id another client deposited with before it */
+pub fn shared_tx_id(transactions: &[Transaction]) -> Option<Transaction> {
+    let mut owners = TxTable::new();
+    transactions
+        .iter()
+        .filter(|transaction| transaction.tx_type == TransactionType::Deposit)
+        .find(|transaction| {
+            let owner = *owners.get(transaction.tx).unwrap_or(&transaction.client);
+            owners.insert(transaction.tx, owner);
+            owner != transaction.client
+        })
+        .copied()
+}
+
+pub fn parse_transactions(transactions: &[Transaction], shards: usize, config: Config) -> Outcome {
+    let mut ledger = ShardedLedger::new(shards, config);
+    transactions
+        .iter()
+        .for_each(|transaction| ledger.apply(transaction));
+    ledger.finish()
+}
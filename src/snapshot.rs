@@ -0,0 +1,329 @@
+use crate::account;
+use crate::checkpoint::Checkpoint;
+use crate::currency::Currency;
+use crate::deposit::{Deposit, DepositState};
+use crate::follow::write_atomically;
+use crate::ledger::State;
+use crate::timestamp::Timestamp;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+
+/*
+ * A ledger's `State` in a small binary file, so a later run can pick up where
+ * this one left off, along with how far into its input it got. Everything is
+ * little-endian:
+ *
+ *   magic      8 bytes  "TPESNAP\0"
+ *   version    u32
+ *   position   u64      rows applied so far
+ *   latest     u8 + u64 whether there's a latest timestamp, and which
+ *   accounts   u32 count, then per account:
+ *                client u16, available i64, held i64, total i64, locked u8,
+ *                receivable i64
+ *   deposits   u64 count, then per deposit:
+ *                tx u32, amount i64, client u16, state u8
+ *   expiring   u64 count, then per deposit: closes u64, tx u32
+ *   expired    u64 count, then per deposit: tx u32, client u16
+ *   forgetting u64 count, then per deposit: forgotten u64, tx u32
+ *   shortfalls u64 count, then per dispute: tx u32, shortfall i64
+ *   withdrawn  u64 count, then per client: client u16, count u64, total i64
+ *   withdrawals u64 count, then per withdrawal: client u16, at u64, amount i64
+ *   rules      u32 count, then per rule:
+ *                name u32 length and that many bytes of UTF-8,
+ *                u64 count, then per client: client u16, value u64
+ *   input      u8       whether there's a checkpoint, and if so:
+ *                offset u64, rows u64, fingerprint u32,
+ *                source u32 length and that many bytes of UTF-8
+ *
+ * The version goes up whenever any of that changes, and only the current one
+ * is read. The config isn't part of it: a run that continues from a snapshot
+ * has to be given the same dispute window, overdraft policy, withdrawal
+ * limits and rules as the one that wrote it.
+ * */
+
+const MAGIC: &[u8; 8] = b"TPESNAP\0";
+pub const VERSION: u32 = 3;
+
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Snapshot {
+    pub state: State,
+    pub input: Option<Checkpoint>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn state_byte(state: DepositState) -> u8 {
+    match state {
+        DepositState::Deposited => 0,
+        DepositState::Disputed => 1,
+        DepositState::ChargedBack => 2,
+    }
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let state = &snapshot.state;
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&state.position.to_le_bytes());
+    bytes.push(state.latest.is_some() as u8);
+    bytes.extend_from_slice(&state.latest.map_or(0, |latest| latest.0).to_le_bytes());
+
+    bytes.extend_from_slice(&(state.accounts.len() as u32).to_le_bytes());
+    state.accounts.iter().for_each(|account| {
+        bytes.extend_from_slice(&account.client().to_le_bytes());
+        bytes.extend_from_slice(&account.available().0.to_le_bytes());
+        bytes.extend_from_slice(&account.held().0.to_le_bytes());
+        bytes.extend_from_slice(&account.total().0.to_le_bytes());
+        bytes.push(account.locked() as u8);
+        bytes.extend_from_slice(&account.receivable().0.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.deposits.len() as u64).to_le_bytes());
+    state.deposits.iter().for_each(|(tx, deposit)| {
+        bytes.extend_from_slice(&tx.to_le_bytes());
+        bytes.extend_from_slice(&deposit.amount.0.to_le_bytes());
+        bytes.extend_from_slice(&deposit.client.to_le_bytes());
+        bytes.push(state_byte(deposit.state));
+    });
+    bytes.extend_from_slice(&(state.expiring.len() as u64).to_le_bytes());
+    state.expiring.iter().for_each(|(closes, tx)| {
+        bytes.extend_from_slice(&closes.to_le_bytes());
+        bytes.extend_from_slice(&tx.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.expired.len() as u64).to_le_bytes());
+    state.expired.iter().for_each(|(tx, client)| {
+        bytes.extend_from_slice(&tx.to_le_bytes());
+        bytes.extend_from_slice(&client.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.forgetting.len() as u64).to_le_bytes());
+    state.forgetting.iter().for_each(|(forgotten, tx)| {
+        bytes.extend_from_slice(&forgotten.to_le_bytes());
+        bytes.extend_from_slice(&tx.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.shortfalls.len() as u64).to_le_bytes());
+    state.shortfalls.iter().for_each(|(tx, shortfall)| {
+        bytes.extend_from_slice(&tx.to_le_bytes());
+        bytes.extend_from_slice(&shortfall.0.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.withdrawn.len() as u64).to_le_bytes());
+    state.withdrawn.iter().for_each(|(client, count, total)| {
+        bytes.extend_from_slice(&client.to_le_bytes());
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(&total.0.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.withdrawals.len() as u64).to_le_bytes());
+    state.withdrawals.iter().for_each(|(client, at, amount)| {
+        bytes.extend_from_slice(&client.to_le_bytes());
+        bytes.extend_from_slice(&at.to_le_bytes());
+        bytes.extend_from_slice(&amount.0.to_le_bytes());
+    });
+    bytes.extend_from_slice(&(state.rules.len() as u32).to_le_bytes());
+    state.rules.iter().for_each(|(name, remembered)| {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(remembered.len() as u64).to_le_bytes());
+        remembered.iter().for_each(|(client, value)| {
+            bytes.extend_from_slice(&client.to_le_bytes());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        });
+    });
+    bytes.push(snapshot.input.is_some() as u8);
+    if let Some(input) = &snapshot.input {
+        bytes.extend_from_slice(&input.offset.to_le_bytes());
+        bytes.extend_from_slice(&input.rows.to_le_bytes());
+        bytes.extend_from_slice(&input.fingerprint.to_le_bytes());
+        bytes.extend_from_slice(&(input.source.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(input.source.as_bytes());
+    }
+    bytes
+}
+
+/*
+ * Reads fixed size fields off the front of the input, and complains if it
+ * runs out.
+ * */
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        if self.bytes.len() < N {
+            return Err(invalid("snapshot is cut short".to_string()));
+        }
+        let (field, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(field.try_into().expect("split at N"))
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn currency(&mut self) -> io::Result<Currency> {
+        Ok(Currency(i64::from_le_bytes(self.take()?)))
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(invalid(format!("{} is not a boolean", other))),
+        }
+    }
+
+    /* A u32 length, and that many bytes of UTF-8 */
+    fn string(&mut self) -> io::Result<String> {
+        let length = self.u32()? as u64;
+        let bytes = self.list(length, 1, |fields| fields.u8())?;
+        String::from_utf8(bytes).map_err(|e| invalid(format!("not UTF-8: {:?}", e)))
+    }
+
+    /* Counts come from the file, so they're capped by what's actually left */
+    fn list<T, F>(&mut self, count: u64, size: usize, mut f: F) -> io::Result<Vec<T>>
+    where
+        F: FnMut(&mut Fields<'a>) -> io::Result<T>,
+    {
+        if count > (self.bytes.len() / size) as u64 {
+            return Err(invalid("snapshot is cut short".to_string()));
+        }
+        (0..count).map(|_| f(self)).collect()
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<Snapshot> {
+    let mut fields = Fields { bytes };
+    if &fields.take::<8>()? != MAGIC {
+        return Err(invalid("not a snapshot".to_string()));
+    }
+    match fields.u32()? {
+        VERSION => (),
+        other => {
+            return Err(invalid(format!(
+                "snapshot version {} can't be read, only {}",
+                other, VERSION
+            )))
+        }
+    }
+
+    let position = fields.u64()?;
+    let latest = match (fields.bool()?, fields.u64()?) {
+        (true, latest) => Some(Timestamp(latest)),
+        (false, _) => None,
+    };
+    let count = fields.u32()? as u64;
+    let accounts = fields.list(count, 35, |fields| {
+        let client = fields.u16()?;
+        let (available, held, total) = (fields.currency()?, fields.currency()?, fields.currency()?);
+        let locked = fields.bool()?;
+        let receivable = fields.currency()?;
+        account::restore(client, available, held, receivable, locked)
+            .filter(|account| account.total() == total)
+            .ok_or_else(|| invalid(format!("account {} doesn't add up", client)))
+    })?;
+    let count = fields.u64()?;
+    let deposits = fields.list(count, 15, |fields| {
+        Ok((
+            fields.u32()?,
+            Deposit {
+                amount: fields.currency()?,
+                client: fields.u16()?,
+                state: match fields.u8()? {
+                    0 => DepositState::Deposited,
+                    1 => DepositState::Disputed,
+                    2 => DepositState::ChargedBack,
+                    other => return Err(invalid(format!("unknown deposit state {}", other))),
+                },
+            },
+        ))
+    })?;
+    let count = fields.u64()?;
+    let expiring = fields.list(count, 12, |fields| Ok((fields.u64()?, fields.u32()?)))?;
+    let count = fields.u64()?;
+    let expired = fields.list(count, 6, |fields| Ok((fields.u32()?, fields.u16()?)))?;
+    let count = fields.u64()?;
+    let forgetting = fields.list(count, 12, |fields| Ok((fields.u64()?, fields.u32()?)))?;
+    let count = fields.u64()?;
+    let shortfalls = fields.list(count, 12, |fields| Ok((fields.u32()?, fields.currency()?)))?;
+    let count = fields.u64()?;
+    let withdrawn = fields.list(count, 18, |fields| {
+        Ok((fields.u16()?, fields.u64()?, fields.currency()?))
+    })?;
+    let count = fields.u64()?;
+    let withdrawals = fields.list(count, 18, |fields| {
+        Ok((fields.u16()?, fields.u64()?, fields.currency()?))
+    })?;
+    let count = fields.u32()? as u64;
+    let rules = fields.list(count, 12, |fields| {
+        let name = fields.string()?;
+        let count = fields.u64()?;
+        Ok((
+            name,
+            fields.list(count, 10, |fields| Ok((fields.u16()?, fields.u64()?)))?,
+        ))
+    })?;
+    let input = match fields.bool()? {
+        false => None,
+        true => {
+            let offset = fields.u64()?;
+            let rows = fields.u64()?;
+            let fingerprint = fields.u32()?;
+            Some(Checkpoint {
+                source: fields.string()?.into(),
+                offset,
+                rows,
+                fingerprint,
+            })
+        }
+    };
+
+    if !fields.bytes.is_empty() {
+        return Err(invalid(format!(
+            "{} bytes left after the snapshot",
+            fields.bytes.len()
+        )));
+    }
+    Ok(Snapshot {
+        state: State {
+            position,
+            latest,
+            accounts,
+            deposits,
+            expiring,
+            expired,
+            forgetting,
+            shortfalls,
+            withdrawn,
+            withdrawals,
+            rules,
+        },
+        input,
+    })
+}
+
+pub fn write(path: &str, snapshot: &Snapshot) -> io::Result<()> {
+    write_atomically(path, &encode(snapshot))
+}
+
+/* None if there's no snapshot yet */
+pub fn read(path: &str) -> io::Result<Option<Snapshot>> {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -1,10 +1,36 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::account::Account;
-    use crate::currency::from_float;
-    use crate::error::{TransactionError, UnsettledReason};
+    use crate::account::{self, Account};
+    use crate::checkpoint::{self, Checkpoint};
+    use crate::clients;
+    use crate::config;
+    use crate::currency::{from_float, Currency};
+    use crate::deposit::{Deposit, DepositState, DepositStore, BYTES_PER_DEPOSIT};
+    use crate::disk_store::{DiskStore, BYTES_PER_HOT_DEPOSIT};
+    use crate::error::{TransactionError, UnsettledReason, Validation};
+    use crate::events::{self, Event};
+    use crate::follow;
+    use crate::history::{Changed, History};
+    use crate::invariants::{self, Checker, Violation};
+    use crate::journal::{self, Book, Journal, JournalingLedger};
     use crate::ledger;
+    use crate::limits::{Limit, WithdrawalLimits};
+    use crate::mmap_reader;
+    use crate::parallel_reader;
+    use crate::query::{self, AsOf};
+    use crate::reader;
+    use crate::reorder::{self, LatePolicy, Order, ReorderBuffer};
+    use crate::rules::{self, Rule, Verdict};
+    use crate::sharded_ledger;
+    use crate::snapshot;
+    use crate::timestamp::{self, Timestamp};
     use crate::transaction::{Transaction, TransactionType};
+    use crate::tx_table::TxTable;
+    use crate::validation;
+    use crate::wal::{self, DurableLedger};
+    use crate::writer;
+    use std::collections::BTreeMap;
     #[test]
     fn it_should_handle_deposits_and_withdrawals() {
         let transactions = vec![
@@ -13,34 +39,32 @@ mod tests {
                 client: 0,
                 tx: 0,
                 amount: from_float(5.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Withdrawal,
                 client: 0,
                 tx: 1,
                 amount: from_float(2.5),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Withdrawal,
                 client: 0,
                 tx: 2,
                 amount: from_float(25.0),
+                timestamp: None,
             },
         ];
         let (parsed, failed) = ledger::parse_transactions(&transactions);
-        let output = vec![Account {
-            client: 0,
-            available: from_float(2.5),
-            held: from_float(0.0),
-            total: from_float(2.5),
-            locked: false,
-        }];
+        let output = vec![account(0, 2.5, 0.0, false)];
         let failed_output = vec![TransactionError::UnsettledWithdrawal(
             Transaction {
                 tx_type: TransactionType::Withdrawal,
                 client: 0,
                 tx: 2,
                 amount: from_float(25.0),
+                timestamp: None,
             },
             UnsettledReason::InsufficientFunds,
         )];
@@ -56,22 +80,18 @@ mod tests {
                 client: 0,
                 tx: 0,
                 amount: from_float(5.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Dispute,
                 client: 0,
                 tx: 0,
                 amount: from_float(0.0),
+                timestamp: None,
             },
         ];
         let (parsed, failed) = ledger::parse_transactions(&transactions);
-        let output = vec![Account {
-            client: 0,
-            available: from_float(0.0),
-            held: from_float(5.0),
-            total: from_float(5.0),
-            locked: false,
-        }];
+        let output = vec![account(0, 0.0, 5.0, false)];
         assert_eq!(parsed, output);
         assert_eq!(failed, vec![]);
     }
@@ -83,28 +103,25 @@ mod tests {
                 client: 0,
                 tx: 0,
                 amount: from_float(5.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Dispute,
                 client: 0,
                 tx: 0,
                 amount: from_float(0.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Resolve,
                 client: 0,
                 tx: 0,
                 amount: from_float(0.0),
+                timestamp: None,
             },
         ];
         let (parsed, failed) = ledger::parse_transactions(&transactions);
-        let output = vec![Account {
-            client: 0,
-            available: from_float(5.0),
-            held: from_float(0.0),
-            total: from_float(5.0),
-            locked: false,
-        }];
+        let output = vec![account(0, 5.0, 0.0, false)];
         assert_eq!(parsed, output);
         assert_eq!(failed, vec![]);
     }
@@ -116,29 +133,2435 @@ mod tests {
                 client: 0,
                 tx: 0,
                 amount: from_float(5.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Dispute,
                 client: 0,
                 tx: 0,
                 amount: from_float(0.0),
+                timestamp: None,
             },
             Transaction {
                 tx_type: TransactionType::Chargeback,
                 client: 0,
                 tx: 0,
                 amount: from_float(0.0),
+                timestamp: None,
             },
         ];
         let (parsed, failed) = ledger::parse_transactions(&transactions);
-        let output = vec![Account {
-            client: 0,
-            available: from_float(0.0),
-            held: from_float(0.0),
-            total: from_float(0.0),
-            locked: true,
-        }];
+        let output = vec![account(0, 0.0, 0.0, true)];
         assert_eq!(parsed, output);
         assert_eq!(failed, vec![]);
     }
+
+    fn write_input(name: &str, contents: &str) -> String {
+        write_bytes(name, contents.as_bytes())
+    }
+
+    fn write_bytes(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "toy_payment_engine_{}_{}.csv",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn it_should_validate_a_file_without_applying_it() {
+        let path = write_input(
+            "validate",
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             deposit,1,2,-1.0\n\
+             withdrawal,1,3,0\n\
+             deposit,1,1,2.0\n\
+             dispute,1,1,3.0\n\
+             resolve,1,42,\n\
+             deposit,one,4,1.0\n\
+             dispute,1,3,\n\
+             dispute,2,1,\n",
+        );
+        let problems = validation::validate_file_from_path(&path).unwrap();
+        let lines = problems
+            .iter()
+            .map(|problem| match problem {
+                Validation::MalformedRow(line, _) => ("malformed", *line),
+                Validation::NegativeAmount(line, _) => ("negative", *line),
+                Validation::ZeroAmount(line, _) => ("zero", *line),
+                Validation::UnexpectedAmount(line, _) => ("amount", *line),
+                Validation::DuplicateTransaction(line, _) => ("duplicate", *line),
+                Validation::UnknownTransaction(line, _) => ("unknown", *line),
+                Validation::OutOfOrder(line, _) => ("out of order", *line),
+            })
+            .collect::<Vec<(&str, u64)>>();
+        assert_eq!(
+            lines,
+            vec![
+                ("negative", 3),
+                ("zero", 4),
+                ("duplicate", 5),
+                ("amount", 6),
+                ("unknown", 7),
+                ("malformed", 8),
+                ("unknown", 9),
+                ("unknown", 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_not_report_problems_for_a_clean_file() {
+        let path = write_input(
+            "validate_clean",
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             withdrawal,1,2,1.0\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n",
+        );
+        assert_eq!(validation::validate_file_from_path(&path), Ok(vec![]));
+    }
+
+    fn account(client: u16, available: f64, held: f64, locked: bool) -> Account {
+        account::restore(
+            client,
+            from_float(available),
+            from_float(held),
+            Currency(0),
+            locked,
+        )
+        .unwrap()
+    }
+
+    fn transaction(tx_type: TransactionType, client: u16, tx: u32, amount: f64) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: from_float(amount),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn it_should_reject_negative_amounts() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 0, 0, 5.0),
+            transaction(TransactionType::Deposit, 0, 1, -5.0),
+            transaction(TransactionType::Withdrawal, 0, 2, -5.0),
+        ];
+        let (parsed, failed) = ledger::parse_transactions(&transactions);
+        assert_eq!(parsed, vec![account(0, 5.0, 0.0, false)]);
+        assert_eq!(
+            failed,
+            vec![
+                TransactionError::NegativeAmount(transactions[1]),
+                TransactionError::NegativeAmount(transactions[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_warn_or_reject_zero_amounts() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 0, 0, 5.0),
+            transaction(TransactionType::Withdrawal, 0, 1, 0.0),
+        ];
+
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        assert_eq!(ledger.apply(&transactions[0]), Ok(()));
+        assert_eq!(ledger.apply(&transactions[1]), Ok(()));
+        assert_eq!(
+            ledger.take_warnings(),
+            vec![TransactionError::ZeroAmount(transactions[1])]
+        );
+        /* Didn't go through, so there's nothing to warn about */
+        let accountless = transaction(TransactionType::Withdrawal, 9, 2, 0.0);
+        assert_eq!(
+            ledger.apply(&accountless),
+            Err(TransactionError::AccountlessAction(accountless))
+        );
+        assert_eq!(ledger.take_warnings(), vec![]);
+
+        let (_, failed) = ledger::parse_transactions_with_config(
+            &transactions,
+            ledger::Config {
+                zero_amount: ledger::ZeroAmountPolicy::Reject,
+                ..ledger::Config::default()
+            },
+        );
+        assert_eq!(failed, vec![TransactionError::ZeroAmount(transactions[1])]);
+    }
+
+    #[test]
+    fn it_should_follow_a_growing_file_and_resume_from_its_offset() {
+        let path = write_input(
+            "follow",
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,3",
+        );
+        let output = format!("{}.out", path);
+        let _ = std::fs::remove_file(format!("{}.state", output));
+        let append = |contents: &str| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+        };
+        let available = |follower: &follow::Follower| {
+            follower
+                .ledger()
+                .accounts()
+                .iter()
+                .map(|account| account.available())
+                .collect::<Vec<Currency>>()
+        };
+
+        let mut follower =
+            follow::Follower::resume(&path, &output, ledger::Config::default()).unwrap();
+        assert_eq!(follower.poll().unwrap().rows, 1);
+        assert_eq!(available(&follower), vec![from_float(5.0)]);
+
+        /* The partial line only counts once it's complete */
+        append(".5\n");
+        assert_eq!(follower.poll().unwrap().rows, 1);
+        assert_eq!(available(&follower), vec![from_float(8.5)]);
+        follower.write_snapshot(&output).unwrap();
+
+        append("withdrawal,1,3,1.0\n");
+        let mut resumed =
+            follow::Follower::resume(&path, &output, ledger::Config::default()).unwrap();
+        assert_eq!(resumed.offset(), follower.offset());
+        assert_eq!(resumed.poll().unwrap().rows, 1);
+        assert_eq!(available(&resumed), vec![from_float(7.5)]);
+
+        append("deposit,1,4,0.0\n");
+        let batch = resumed.poll().unwrap();
+        assert_eq!(batch.errors, vec![]);
+        assert_eq!(
+            batch.warnings,
+            vec![TransactionError::ZeroAmount(transaction(
+                TransactionType::Deposit,
+                1,
+                4,
+                0.0
+            ))]
+        );
+
+        /* Deposits from before the restart are still there */
+        append("dispute,1,1,\ndeposit,1,2,1.0\n");
+        let batch = resumed.poll().unwrap();
+        assert_eq!(
+            batch.errors,
+            vec![TransactionError::DuplicateTransaction(transaction(
+                TransactionType::Deposit,
+                1,
+                2,
+                1.0
+            ))]
+        );
+        assert_eq!(resumed.ledger().accounts()[0].held(), from_float(5.0));
+        assert_eq!(resumed.ledger().position(), 6);
+    }
+
+    #[test]
+    fn it_should_resume_following_with_what_clients_owe() {
+        let path = write_input(
+            "follow_receivable",
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,4.0\ndispute,1,1,\n",
+        );
+        let output = format!("{}.out", path);
+        let _ = std::fs::remove_file(format!("{}.state", output));
+        let config = || ledger::Config {
+            overdraft: ledger::OverdraftPolicy::Receivable,
+            ..ledger::Config::default()
+        };
+
+        let mut follower = follow::Follower::resume(&path, &output, config()).unwrap();
+        assert_eq!(follower.poll().unwrap().rows, 3);
+        follower.write_snapshot(&output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "client,available,held,total,locked,receivable\n1,0.0,1.0,1.0,false,4.0\n"
+        );
+
+        let resumed = follow::Follower::resume(&path, &output, config()).unwrap();
+        assert_eq!(resumed.ledger().accounts(), follower.ledger().accounts());
+        assert_eq!(resumed.ledger().accounts()[0].receivable(), from_float(4.0));
+    }
+
+    #[test]
+    fn it_should_round_currency_instead_of_truncating() {
+        assert_eq!(from_float(0.0003), Currency(3));
+        assert_eq!(from_float(2.0001), Currency(20001));
+        assert_eq!(from_float(-0.0003), Currency(-3));
+
+        /* Anything past four digits goes to the nearest, in the input too */
+        let path = write_input(
+            "rounded",
+            "type,client,tx,amount\ndeposit,1,1,2.00016\ndeposit,1,2,2.00014\n",
+        );
+        let amounts = reader::read_file_from_path(&path)
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction.amount)
+            .collect::<Vec<Currency>>();
+        assert_eq!(amounts, vec![Currency(20002), Currency(20001)]);
+
+        /* So whatever's written out as a float reads back the same */
+        (0..10_000).for_each(|amount| {
+            let written = (amount as f64 / 10_000.0).to_string();
+            assert_eq!(from_float(written.parse().unwrap()), Currency(amount));
+        });
+    }
+
+    #[test]
+    fn it_should_read_the_same_transactions_from_a_mapped_file() {
+        let inputs = vec![
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\ndispute,1,1,\n",
+            "type,client,tx,amount\r\ndeposit,1,1,5.0\r\n\r\n\"deposit\",0x2,3,0.0003",
+            "\u{feff}client,type,amount,tx,extra\n3,deposit,1.0,7,x\n",
+            "tx,client,type,amount\n1,1,deposit,1.0\n2,1,chargeback\n",
+            "type,client,tx,amount\ndeposit,1,1, 5.0\n",
+            "type,client,tx,amount\ndeposit,1,1,5.0,6.0\n",
+            "type,client,tx\ndeposit,1,1\n",
+            "type,client,tx,amount\ndeposit,1,1,inf\n",
+            "type,client,tx,amount\ndeposit,1,1,NaN\n",
+            "type,client,tx,amount\n",
+            "",
+        ];
+        /* A character split across two fields */
+        let split = b"type,client,tx,amount\ndeposit,1\xc3,\xa9,1.0\n";
+        let matching = inputs
+            .iter()
+            .map(|input| input.as_bytes())
+            .chain(std::iter::once(&split[..]))
+            .enumerate()
+            .filter(|(index, input)| {
+                let path = write_bytes(&format!("mmap_{}", index), input);
+                match (
+                    reader::read_file_from_path(&path),
+                    mmap_reader::read_file_from_path(&path),
+                ) {
+                    (Ok(expected), Ok(actual)) => {
+                        assert_eq!(expected, actual, "{:?}", input);
+                        true
+                    }
+                    (Err(_), Err(_)) => false,
+                    (expected, actual) => panic!("{:?}: {:?} != {:?}", input, expected, actual),
+                }
+            })
+            .count();
+        assert_eq!(matching, 5);
+    }
+
+    fn generated_input(rows: u32) -> String {
+        let mut input = String::from("type,client,tx,amount\n");
+        (0..rows).for_each(|tx| {
+            let client = tx % 50;
+            input.push_str(&match tx % 10 {
+                0..=5 => format!("deposit,{},{},{}.{}\n", client, tx, tx % 100, tx % 9999),
+                6 | 7 => format!("withdrawal,{},{},1.5\n", client, tx),
+                8 => format!("dispute,{},{},\n", client, tx - 8),
+                _ => format!("chargeback,{},{},\n", client, tx - 9),
+            })
+        });
+        input
+    }
+
+    fn sorted(mut accounts: Vec<Account>) -> Vec<Account> {
+        accounts.sort_by_key(|account| account.client());
+        accounts
+    }
+
+    #[test]
+    fn it_should_split_chunks_on_line_boundaries() {
+        let input = generated_input(100);
+        let chunks = parallel_reader::split_lines(input.as_bytes(), 64);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.ends_with(b"\n")));
+        assert_eq!(chunks.concat(), input.as_bytes());
+    }
+
+    #[test]
+    fn it_should_parse_in_parallel_and_apply_in_order() {
+        let input = generated_input(50_000);
+        let path = write_input("parallel", &input);
+        let sequential = reader::read_file_from_path(&path).unwrap();
+        assert_eq!(
+            parallel_reader::read_file_from_path(&path, 4),
+            Ok(sequential.clone())
+        );
+
+        let (accounts, failed) = ledger::parse_transactions(&sequential);
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let streamed = parallel_reader::apply_file_from_path(&path, 4, &mut ledger).unwrap();
+        assert_eq!(streamed, failed);
+        assert_eq!(sorted(ledger.into_accounts()), sorted(accounts));
+
+        let broken = write_input("parallel_broken", &format!("{}deposit,x,1,1.0\n", input));
+        assert!(parallel_reader::read_file_from_path(&broken, 4).is_err());
+        let split = write_bytes(
+            "parallel_split",
+            &[input.as_bytes(), b"deposit,1\xc3,\xa9,1.0\n"].concat(),
+        );
+        assert!(parallel_reader::read_file_from_path(&split, 2).is_err());
+        assert!(mmap_reader::read_file_from_path(&split).is_err());
+    }
+
+    #[test]
+    fn it_should_not_dispute_deposits_of_other_clients() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 0, 0, 5.0),
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Dispute, 1, 0, 0.0),
+        ];
+        let (_, failed) = ledger::parse_transactions(&transactions);
+        assert_eq!(
+            failed,
+            vec![TransactionError::UnsettledDispute(
+                transactions[2],
+                UnsettledReason::TransactionOrAccountNotFound
+            )]
+        );
+    }
+
+    #[test]
+    fn it_should_shard_by_client_and_match_a_single_ledger() {
+        let path = write_input("sharded", &generated_input(20_000));
+        let transactions = reader::read_file_from_path(&path).unwrap();
+
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let errors = transactions
+            .iter()
+            .filter_map(|transaction| ledger.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+        let warnings = ledger.take_warnings();
+        let expected = ledger::Outcome {
+            accounts: sorted(ledger.into_accounts()),
+            errors,
+            warnings,
+        };
+        assert!(!expected.errors.is_empty());
+        assert!(!expected.warnings.is_empty());
+
+        [1, 3, 8].iter().for_each(|shards| {
+            assert_eq!(
+                sharded_ledger::parse_transactions(
+                    &transactions,
+                    *shards,
+                    ledger::Config::default()
+                ),
+                expected
+            );
+        });
+        assert_eq!(sharded_ledger::shared_tx_id(&transactions), None);
+    }
+
+    #[test]
+    fn it_should_only_shard_deposits_with_tx_ids_of_their_own() {
+        /* A client reusing its own tx id is refused the same way in any shard */
+        let reused = vec![
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Deposit, 1, 1, 3.0),
+            transaction(TransactionType::Deposit, 2, 2, 3.0),
+            transaction(TransactionType::Dispute, 2, 1, 0.0),
+        ];
+        assert_eq!(sharded_ledger::shared_tx_id(&reused), None);
+        let (accounts, errors) = ledger::parse_transactions(&reused);
+        let outcome = sharded_ledger::parse_transactions(&reused, 2, ledger::Config::default());
+        assert_eq!(
+            (sorted(outcome.accounts), outcome.errors),
+            (sorted(accounts), errors)
+        );
+
+        /* Another client's can't be told apart without the first shard */
+        let shared = vec![
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Withdrawal, 2, 1, 3.0),
+            transaction(TransactionType::Deposit, 2, 1, 3.0),
+            transaction(TransactionType::Deposit, 3, 1, 3.0),
+        ];
+        assert_eq!(sharded_ledger::shared_tx_id(&shared), Some(shared[2]));
+    }
+
+    #[test]
+    fn it_should_store_entries_by_tx_id() {
+        let mut table = TxTable::new();
+        assert_eq!(table.insert(5, 'a'), None);
+        assert_eq!(table.insert(u32::MAX, 'b'), None);
+        assert_eq!(table.insert(5, 'c'), Some('a'));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(5), Some(&'c'));
+        assert_eq!(table.get(6), None);
+        assert_eq!(
+            table.iter().collect::<Vec<(u32, &char)>>(),
+            vec![(5, &'c'), (u32::MAX, &'b')]
+        );
+        assert_eq!(table.remove(5), Some('c'));
+        assert_eq!(table.remove(5), None);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.remove(u32::MAX), Some('b'));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn it_should_only_allocate_pages_for_tx_ids_close_together() {
+        /* One tx id every page doesn't take a page each */
+        let mut table = TxTable::new();
+        let sparse = (0..10_000).map(|n| n * 4096 + 7).collect::<Vec<u32>>();
+        sparse.iter().for_each(|tx| {
+            table.insert(*tx, *tx);
+        });
+        assert_eq!(table.pages(), 0);
+
+        /* A page that fills up is allocated, and given up once it empties */
+        let dense = (1 << 26..(1 << 26) + 4096).collect::<Vec<u32>>();
+        dense.iter().take(511).for_each(|tx| {
+            table.insert(*tx, *tx);
+        });
+        assert_eq!(table.pages(), 0);
+        dense.iter().for_each(|tx| {
+            table.insert(*tx, *tx);
+        });
+        assert_eq!(table.pages(), 1);
+        assert_eq!(table.len(), sparse.len() + dense.len());
+        let mut all = sparse
+            .iter()
+            .chain(dense.iter())
+            .copied()
+            .collect::<Vec<u32>>();
+        all.sort_unstable();
+        assert_eq!(
+            table
+                .iter()
+                .map(|(tx, value)| (tx, *value))
+                .collect::<Vec<(u32, u32)>>(),
+            all.iter().map(|tx| (*tx, *tx)).collect::<Vec<(u32, u32)>>()
+        );
+        dense.iter().skip(10).for_each(|tx| {
+            assert_eq!(table.remove(*tx), Some(*tx));
+        });
+        assert_eq!(table.pages(), 0);
+        assert_eq!(table.get(dense[9]), Some(&dense[9]));
+        assert_eq!(table.get(sparse[9]), Some(&sparse[9]));
+        assert_eq!(table.len(), sparse.len() + 10);
+    }
+
+    #[test]
+    fn it_should_keep_tracked_deposits_compact() {
+        assert_eq!(std::mem::size_of::<Deposit>(), BYTES_PER_DEPOSIT);
+        assert_eq!(std::mem::size_of::<Option<Deposit>>(), BYTES_PER_DEPOSIT);
+    }
+
+    #[test]
+    fn it_should_only_resolve_or_chargeback_what_is_disputed() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 0, 0, 5.0),
+            transaction(TransactionType::Resolve, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+            transaction(TransactionType::Resolve, 0, 0, 0.0),
+            transaction(TransactionType::Chargeback, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+            transaction(TransactionType::Chargeback, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+        ];
+        let (parsed, failed) = ledger::parse_transactions(&transactions);
+        assert_eq!(parsed, vec![account(0, 0.0, 0.0, true)]);
+        assert_eq!(
+            failed,
+            vec![
+                TransactionError::UnsettledResolve(
+                    transactions[1],
+                    UnsettledReason::InvalidState(DepositState::Deposited)
+                ),
+                TransactionError::UnsettledDispute(
+                    transactions[3],
+                    UnsettledReason::InvalidState(DepositState::Disputed)
+                ),
+                TransactionError::UnsettledChargeback(
+                    transactions[5],
+                    UnsettledReason::InvalidState(DepositState::Deposited)
+                ),
+                TransactionError::UnsettledDispute(
+                    transactions[8],
+                    UnsettledReason::InvalidState(DepositState::ChargedBack)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_refuse_a_deposit_that_reuses_a_tx_id() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Deposit, 1, 1, 3.0),
+            transaction(TransactionType::Deposit, 2, 1, 3.0),
+            transaction(TransactionType::Resolve, 1, 1, 0.0),
+        ];
+        let (parsed, failed) = ledger::parse_transactions(&transactions);
+        assert_eq!(parsed, vec![account(1, 10.0, 0.0, false)]);
+        assert_eq!(
+            failed,
+            vec![
+                TransactionError::DuplicateTransaction(transactions[2]),
+                TransactionError::DuplicateTransaction(transactions[3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_spill_deposits_to_disk_and_read_them_back() {
+        let path = write_input("spilled_deposits", "");
+        let mut store = DiskStore::create(&path, 4 * BYTES_PER_HOT_DEPOSIT).unwrap();
+        let deposit = |tx: u32| Deposit {
+            amount: from_float(tx as f64),
+            client: tx as u16,
+            state: DepositState::Deposited,
+        };
+        (0..100).for_each(|tx| store.insert(tx, deposit(tx)).unwrap());
+        assert_eq!(store.in_memory(), 4);
+        assert_eq!(store.get(3).unwrap(), Some(deposit(3)));
+        assert_eq!(store.get(200).unwrap(), None);
+
+        let disputed = Deposit {
+            state: DepositState::Disputed,
+            ..deposit(7)
+        };
+        store.insert(7, disputed).unwrap();
+        (100..110).for_each(|tx| store.insert(tx, deposit(tx)).unwrap());
+        assert_eq!(store.get(7).unwrap(), Some(disputed));
+
+        assert_eq!(store.remove(3).unwrap(), Some(deposit(3)));
+        (110..120).for_each(|tx| store.insert(tx, deposit(tx)).unwrap());
+        assert_eq!(store.get(3).unwrap(), None);
+
+        /* Looking a deposit up keeps it around, however often it's touched */
+        (0..1000).for_each(|_| {
+            store.insert(116, disputed).unwrap();
+            store.get(117).unwrap();
+        });
+        store.insert(120, deposit(120)).unwrap();
+        store.insert(121, deposit(121)).unwrap();
+        assert_eq!(store.in_memory(), 4);
+        assert!(store.holds(116) && store.holds(117));
+        assert!(!store.holds(118) && !store.holds(119));
+        assert_eq!(store.get(116).unwrap(), Some(disputed));
+
+        drop(store);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn it_should_match_the_in_memory_ledger_when_spilling() {
+        let path = write_input("spilled", &generated_input(20_000));
+        let transactions = reader::read_file_from_path(&path).unwrap();
+        let (accounts, failed) = ledger::parse_transactions(&transactions);
+
+        let store = DiskStore::create(&format!("{}.deposits", path), 64).unwrap();
+        let mut ledger = ledger::Ledger::with_store(ledger::Config::default(), store);
+        let errors = transactions
+            .iter()
+            .filter_map(|transaction| ledger.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+        assert_eq!(errors, failed);
+        assert_eq!(ledger.into_accounts(), accounts);
+    }
+
+    #[test]
+    fn it_should_only_dispute_deposits_within_the_window() {
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 0, 0, 5.0),
+            transaction(TransactionType::Deposit, 0, 1, 3.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+            transaction(TransactionType::Deposit, 0, 2, 1.0),
+            transaction(TransactionType::Dispute, 0, 1, 0.0),
+            transaction(TransactionType::Resolve, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 0, 0, 0.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            transaction(TransactionType::Dispute, 0, 2, 0.0),
+        ];
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Transactions(2)),
+            ..ledger::Config::default()
+        };
+        let (parsed, failed) = ledger::parse_transactions_with_config(&transactions, config);
+        assert_eq!(parsed[0].available(), from_float(9.0));
+        assert_eq!(parsed[0].held(), from_float(0.0));
+        assert_eq!(
+            failed,
+            vec![
+                TransactionError::DisputeWindowExpired(transactions[4]),
+                TransactionError::DisputeWindowExpired(transactions[6]),
+                TransactionError::UnsettledDispute(
+                    transactions[7],
+                    UnsettledReason::TransactionOrAccountNotFound
+                ),
+                /* A window after it expired, the deposit is forgotten altogether */
+                TransactionError::UnsettledDispute(
+                    transactions[8],
+                    UnsettledReason::TransactionOrAccountNotFound
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_forget_expired_deposits_a_window_later() {
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Transactions(100)),
+            ..ledger::Config::default()
+        };
+        let mut ledger = ledger::Ledger::new(config);
+        (0..100_000).for_each(|tx| {
+            ledger
+                .apply(&transaction(
+                    TransactionType::Deposit,
+                    (tx % 7) as u16,
+                    tx,
+                    1.0,
+                ))
+                .unwrap();
+        });
+        let state = ledger.state();
+        /* The ones still in their window, the latest one included */
+        assert_eq!(state.deposits.len(), 101);
+        assert_eq!(state.expired.len(), 100);
+        assert_eq!(state.forgetting.len(), 100);
+
+        /* Too late to even tell it was there, so its tx id can be used again */
+        assert_eq!(
+            ledger.apply(&transaction(TransactionType::Dispute, 0, 0, 0.0)),
+            Err(TransactionError::UnsettledDispute(
+                transaction(TransactionType::Dispute, 0, 0, 0.0),
+                UnsettledReason::TransactionOrAccountNotFound
+            ))
+        );
+        assert_eq!(
+            ledger.apply(&transaction(TransactionType::Deposit, 0, 0, 1.0)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn it_should_count_the_dispute_window_over_all_shards() {
+        /* Every deposit gets disputed and resolved inside the window, and disputed again after */
+        let client = |tx: u32| (tx / 5 % 5) as u16;
+        let transactions = (10..5000)
+            .map(|tx| match tx % 5 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Resolve, client(tx), tx - 3, 0.0),
+                _ => transaction(TransactionType::Dispute, client(tx - 9), tx - 9, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Transactions(5)),
+            ..ledger::Config::default()
+        };
+
+        let mut ledger = ledger::Ledger::new(config.clone());
+        let errors = transactions
+            .iter()
+            .filter_map(|transaction| ledger.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+        assert_eq!(
+            errors
+                .iter()
+                .filter(|e| matches!(e, TransactionError::DisputeWindowExpired(_)))
+                .count(),
+            997
+        );
+
+        let outcome = sharded_ledger::parse_transactions(&transactions, 3, config);
+        assert_eq!(outcome.errors, errors);
+        assert_eq!(outcome.accounts, ledger.into_accounts());
+    }
+
+    #[test]
+    fn it_should_read_a_run_from_its_flags() {
+        let run = |flags: &[&str]| {
+            let flags = flags.iter().map(|f| f.to_string()).collect::<Vec<String>>();
+            let flags = flags.iter().collect::<Vec<&String>>();
+            config::ledger(&flags).and_then(|ledger| config::run(&flags, ledger))
+        };
+        let options = run(&["--dispute-window=7d", "--shards=4", "--review=flagged.csv"]).unwrap();
+        assert_eq!(
+            options.ledger.dispute_window,
+            Some(ledger::DisputeWindow::Time(std::time::Duration::from_secs(
+                7 * 24 * 60 * 60
+            )))
+        );
+        assert_eq!(options.input, config::Input::Csv);
+        assert_eq!(options.shards, Some(4));
+        assert_eq!(options.store, config::Store::Memory);
+        assert_eq!(options.review, Some("flagged.csv".to_string()));
+
+        let options = run(&["--spill-to=/tmp/deposits", "--memory-budget=2", "--mmap"]).unwrap();
+        assert_eq!(
+            options.store,
+            config::Store::Disk("/tmp/deposits".to_string(), 2 * 1024 * 1024)
+        );
+        assert_eq!(options.input, config::Input::Mapped);
+        let options = run(&["--reorder-window=5s", "--late=recompute"]).unwrap();
+        assert_eq!(options.reorder.unwrap().late, LatePolicy::Recompute);
+
+        [
+            &["--spill-to=/tmp/deposits", "--shards=2"][..],
+            &["--wal=log"],
+            &["--snapshot=snap", "--threads=2"],
+            &["--events=events.csv", "--journal=journal.csv"],
+            &["--events=events.csv", "--overdraft=receivable"],
+            &["--check-invariants", "--reorder-window=5s"],
+            &[
+                "--reorder-window=5s",
+                "--late=recompute",
+                "--dispute-window=100",
+            ],
+            &[
+                "--reorder-window=5s",
+                "--late=recompute",
+                "--withdrawal-window=1d",
+            ],
+            &["--max-withdrawal=-1"],
+            &["--review"],
+        ]
+        .iter()
+        .for_each(|flags| assert!(run(flags).is_err(), "{:?}", flags));
+    }
+
+    #[test]
+    fn it_should_parse_and_write_timestamps() {
+        let cases = [
+            ("1970-01-01T00:00:00Z", 0),
+            ("2000-02-29T23:59:59.5Z", 951_868_799_500),
+            ("2024-03-01T12:30:00.250Z", 1_709_296_200_250),
+        ];
+        cases.iter().for_each(|(text, millis)| {
+            assert_eq!(timestamp::parse(text), Ok(Timestamp(*millis)));
+        });
+        assert_eq!(
+            Timestamp(951_868_799_500).to_string(),
+            "2000-02-29T23:59:59.500Z"
+        );
+        assert_eq!(
+            Timestamp(1_709_296_200_000).to_string(),
+            "2024-03-01T12:30:00Z"
+        );
+        [
+            "2024-03-01 12:30:00Z",
+            "2024-03-01T12:30:00",
+            "2024-03-01T12:30:00+01:00",
+            "2023-02-29T00:00:00Z",
+            "2024-03-01T24:00:00Z",
+            "2024-03-01T12:30:00.1234Z",
+            "1969-12-31T23:59:59Z",
+            "2024-03-01T12:30:0\u{e9}Z",
+        ]
+        .iter()
+        .for_each(|text| assert!(timestamp::parse(text).is_err(), "{}", text));
+    }
+
+    #[test]
+    fn it_should_read_an_optional_timestamp_column() {
+        let path = write_input(
+            "timestamps",
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,5.0,2024-03-01T12:30:00Z\n\
+             deposit,1,2,1.0,\n",
+        );
+        let transactions = reader::read_file_from_path(&path).unwrap();
+        assert_eq!(
+            transactions[0].timestamp,
+            Some(Timestamp(1_709_296_200_000))
+        );
+        assert_eq!(transactions[1].timestamp, None);
+        assert_eq!(mmap_reader::read_file_from_path(&path), Ok(transactions));
+
+        let broken = write_input(
+            "timestamps_broken",
+            "type,client,tx,amount,timestamp\ndeposit,1,1,5.0,yesterday\n",
+        );
+        assert!(reader::read_file_from_path(&broken).is_err());
+        assert!(mmap_reader::read_file_from_path(&broken).is_err());
+    }
+
+    fn at(transaction: Transaction, text: &str) -> Transaction {
+        Transaction {
+            timestamp: Some(timestamp::parse(text).unwrap()),
+            ..transaction
+        }
+    }
+
+    #[test]
+    fn it_should_reject_transactions_out_of_order() {
+        let transactions = vec![
+            at(
+                transaction(TransactionType::Deposit, 0, 0, 5.0),
+                "2024-03-01T12:00:00Z",
+            ),
+            at(
+                transaction(TransactionType::Deposit, 0, 1, 1.0),
+                "2024-03-01T11:00:00Z",
+            ),
+            transaction(TransactionType::Deposit, 0, 2, 1.0),
+            at(
+                transaction(TransactionType::Deposit, 0, 3, 1.0),
+                "2024-03-01T12:00:00Z",
+            ),
+        ];
+        let (parsed, failed) = ledger::parse_transactions(&transactions);
+        assert_eq!(parsed[0].available(), from_float(8.0));
+        assert!(failed.is_empty());
+
+        let config = ledger::Config {
+            out_of_order: ledger::OutOfOrderPolicy::Reject,
+            ..ledger::Config::default()
+        };
+        let (parsed, failed) = ledger::parse_transactions_with_config(&transactions, config);
+        assert_eq!(parsed[0].available(), from_float(7.0));
+        assert_eq!(failed, vec![TransactionError::OutOfOrder(transactions[1])]);
+    }
+
+    #[test]
+    fn it_should_count_the_dispute_window_in_time() {
+        let transactions = vec![
+            at(
+                transaction(TransactionType::Deposit, 0, 0, 5.0),
+                "2024-03-01T12:00:00Z",
+            ),
+            at(
+                transaction(TransactionType::Deposit, 1, 1, 2.0),
+                "2024-03-01T12:30:00Z",
+            ),
+            at(
+                transaction(TransactionType::Dispute, 1, 1, 0.0),
+                "2024-03-01T13:30:00Z",
+            ),
+            at(
+                transaction(TransactionType::Dispute, 0, 0, 0.0),
+                "2024-03-01T13:00:00.001Z",
+            ),
+        ];
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Time(std::time::Duration::from_secs(
+                60 * 60,
+            ))),
+            ..ledger::Config::default()
+        };
+        let (parsed, failed) =
+            ledger::parse_transactions_with_config(&transactions, config.clone());
+        assert_eq!(parsed[1].held(), from_float(2.0));
+        assert_eq!(
+            failed,
+            vec![TransactionError::DisputeWindowExpired(transactions[3])]
+        );
+        assert_eq!(
+            sharded_ledger::parse_transactions(&transactions, 2, config).errors,
+            failed
+        );
+    }
+
+    #[test]
+    fn it_should_release_transactions_in_order_within_the_window() {
+        let mut buffer = ReorderBuffer::new(Order::Sequence, 2, 10);
+        let mut released = [3, 1, 2, 5, 4, 9, 0]
+            .iter()
+            .map(|tx| transaction(TransactionType::Deposit, 0, *tx, 1.0))
+            .chain(vec![transaction(TransactionType::Dispute, 0, 1, 0.0)])
+            .flat_map(|transaction| buffer.push(transaction))
+            .collect::<Vec<reorder::Released>>();
+        released.append(&mut buffer.flush());
+        let released = released
+            .into_iter()
+            .map(|released| match released {
+                reorder::Released::InOrder(_, _, transaction) => (true, transaction.tx),
+                reorder::Released::Late(_, _, transaction) => (false, transaction.tx),
+            })
+            .collect::<Vec<(bool, u32)>>();
+        assert_eq!(
+            released,
+            vec![
+                (true, 1),
+                (true, 2),
+                (true, 3),
+                (true, 4),
+                (true, 5),
+                (false, 0),
+                (true, 9),
+                (true, 1),
+            ]
+        );
+
+        let mut bounded = ReorderBuffer::new(Order::Sequence, 100, 2);
+        assert!(bounded
+            .push(transaction(TransactionType::Deposit, 0, 2, 1.0))
+            .is_empty());
+        assert!(bounded
+            .push(transaction(TransactionType::Deposit, 0, 1, 1.0))
+            .is_empty());
+        assert_eq!(
+            bounded.push(transaction(TransactionType::Deposit, 0, 3, 1.0)),
+            vec![reorder::Released::InOrder(
+                1,
+                1,
+                transaction(TransactionType::Deposit, 0, 1, 1.0)
+            )]
+        );
+        assert_eq!(bounded.len(), 2);
+    }
+
+    #[test]
+    fn it_should_handle_late_transactions_by_policy() {
+        let transactions = vec![
+            at(
+                transaction(TransactionType::Deposit, 0, 1, 5.0),
+                "2024-03-01T12:00:00Z",
+            ),
+            at(
+                transaction(TransactionType::Withdrawal, 0, 2, 5.0),
+                "2024-03-01T12:00:10Z",
+            ),
+            at(
+                transaction(TransactionType::Deposit, 0, 3, 3.0),
+                "2024-03-01T12:00:20Z",
+            ),
+            at(
+                transaction(TransactionType::Withdrawal, 0, 4, 4.0),
+                "2024-03-01T12:00:05Z",
+            ),
+        ];
+        let reordered = |late: LatePolicy| {
+            let config = reorder::Config {
+                order: Order::Timestamp,
+                window: 1000,
+                capacity: 100,
+                late,
+            };
+            let ledger = ledger::Ledger::new(ledger::Config::default());
+            let mut ledger = reorder::ReorderingLedger::new(ledger, config);
+            transactions
+                .iter()
+                .for_each(|transaction| ledger.apply(transaction));
+            ledger.finish().0
+        };
+
+        let rejected = reordered(LatePolicy::Reject);
+        assert_eq!(rejected.accounts[0].available(), from_float(3.0));
+        assert_eq!(
+            rejected.errors,
+            vec![TransactionError::Late(transactions[3])]
+        );
+
+        let appended = reordered(LatePolicy::Append);
+        assert_eq!(appended.accounts[0].available(), from_float(3.0));
+        assert_eq!(
+            appended.errors,
+            vec![TransactionError::UnsettledWithdrawal(
+                transactions[3],
+                UnsettledReason::InsufficientFunds
+            )]
+        );
+
+        let recomputed = reordered(LatePolicy::Recompute);
+        let mut in_order = transactions.clone();
+        in_order.sort_by_key(|transaction| transaction.timestamp);
+        let (accounts, _) = ledger::parse_transactions(&in_order);
+        assert_eq!(recomputed.accounts, accounts);
+        assert_eq!(recomputed.accounts[0].available(), from_float(4.0));
+        assert!(recomputed.errors.is_empty());
+    }
+
+    #[test]
+    fn it_should_recompute_history_after_an_insert_in_the_past() {
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let mut history = History::new();
+        let transactions = [
+            transaction(TransactionType::Deposit, 0, 1, 5.0),
+            transaction(TransactionType::Withdrawal, 0, 3, 5.0),
+            transaction(TransactionType::Withdrawal, 0, 4, 1.0),
+        ];
+        transactions
+            .iter()
+            .zip([1, 3, 4].iter())
+            .for_each(|(transaction, key)| {
+                history.apply(&mut ledger, *key, transaction).ok();
+            });
+        assert_eq!(
+            history.versions(0)[2].error,
+            Some(TransactionError::UnsettledWithdrawal(
+                transactions[2],
+                UnsettledReason::InsufficientFunds
+            ))
+        );
+
+        let late = transaction(TransactionType::Deposit, 0, 2, 2.0);
+        let recomputed = history.insert(&mut ledger, 2, &late);
+        assert_eq!(recomputed.result, Ok(()));
+        assert_eq!(
+            recomputed.changed,
+            vec![Changed {
+                transaction: transactions[2],
+                before: Some(TransactionError::UnsettledWithdrawal(
+                    transactions[2],
+                    UnsettledReason::InsufficientFunds
+                )),
+                after: None,
+            }]
+        );
+        assert_eq!(
+            history
+                .versions(0)
+                .iter()
+                .map(|version| (
+                    version.transaction.tx,
+                    version.account.map(|account| account.available())
+                ))
+                .collect::<Vec<(u32, Option<Currency>)>>(),
+            vec![
+                (1, Some(from_float(5.0))),
+                (2, Some(from_float(7.0))),
+                (3, Some(from_float(2.0))),
+                (4, Some(from_float(1.0))),
+            ]
+        );
+        assert_eq!(ledger.account(0).unwrap().available(), from_float(1.0));
+        assert!(history.versions(1).is_empty());
+
+        /* Nothing the ledger keeps about the client is counted twice in a replay */
+        let config = ledger::Config {
+            withdrawal_limits: WithdrawalLimits {
+                max_count: Some(2),
+                ..WithdrawalLimits::default()
+            },
+            rules: rules::Config {
+                max_disputes: Some(2),
+                ..rules::Config::default()
+            },
+            ..ledger::Config::default()
+        };
+        let mut ledger = ledger::Ledger::new(config);
+        let mut history = History::new();
+        [
+            (1, transaction(TransactionType::Deposit, 0, 1, 5.0)),
+            (3, transaction(TransactionType::Withdrawal, 0, 3, 1.0)),
+            (3, transaction(TransactionType::Dispute, 0, 1, 0.0)),
+        ]
+        .iter()
+        .for_each(|(key, transaction)| history.apply(&mut ledger, *key, transaction).unwrap());
+        let late = transaction(TransactionType::Deposit, 0, 2, 2.0);
+        assert_eq!(history.insert(&mut ledger, 2, &late).result, Ok(()));
+        [
+            transaction(TransactionType::Withdrawal, 0, 5, 1.0),
+            transaction(TransactionType::Dispute, 0, 2, 0.0),
+            transaction(TransactionType::Resolve, 0, 1, 0.0),
+        ]
+        .iter()
+        .for_each(|transaction| history.apply(&mut ledger, 5, transaction).unwrap());
+        assert_eq!(ledger.take_warnings(), vec![]);
+        assert_eq!(ledger.accounts(), vec![account(0, 3.0, 2.0, false)]);
+    }
+
+    #[test]
+    fn it_should_tell_the_balance_at_a_point_in_time() {
+        let transactions = vec![
+            at(
+                transaction(TransactionType::Deposit, 7, 1, 5.0),
+                "2024-03-01T12:00:00Z",
+            ),
+            at(
+                transaction(TransactionType::Deposit, 8, 2, 3.0),
+                "2024-03-01T12:01:00Z",
+            ),
+            at(
+                transaction(TransactionType::Withdrawal, 7, 3, 9.0),
+                "2024-03-01T12:02:00Z",
+            ),
+            transaction(TransactionType::Withdrawal, 7, 4, 2.0),
+            at(
+                transaction(TransactionType::Deposit, 7, 5, 1.0),
+                "2024-03-01T12:05:00Z",
+            ),
+        ];
+        let config = ledger::Config::default();
+        let after_2 = query::replay(&transactions, 7, AsOf::Tx(2), config.clone()).unwrap();
+        assert_eq!(after_2.account.unwrap().available(), from_float(5.0));
+        assert_eq!(after_2.transactions, vec![transactions[0]]);
+
+        let moment = AsOf::Time(timestamp::parse("2024-03-01T12:04:59Z").unwrap());
+        let before_5 = query::replay(&transactions, 7, moment, config.clone()).unwrap();
+        assert_eq!(before_5.account.unwrap().available(), from_float(3.0));
+        assert_eq!(
+            before_5.transactions,
+            vec![transactions[0], transactions[3]]
+        );
+        assert_eq!(
+            query::replay(&transactions, 8, AsOf::Tx(1), config.clone())
+                .unwrap()
+                .account,
+            None
+        );
+        assert_eq!(
+            query::replay(&transactions, 7, AsOf::Tx(42), config.clone()),
+            None
+        );
+
+        let mut ledger = ledger::Ledger::new(config);
+        let mut history = History::new();
+        transactions
+            .iter()
+            .enumerate()
+            .for_each(|(key, transaction)| {
+                history.apply(&mut ledger, key as u64, transaction).ok();
+            });
+        assert_eq!(query::from_history(&history, 7, moment), Some(before_5));
+        assert_eq!(
+            query::from_history(&history, 7, AsOf::Tx(4)).map(|balance| balance.account),
+            Some(Some(account(7, 3.0, 0.0, false)))
+        );
+        assert_eq!(query::from_history(&history, 7, AsOf::Tx(2)), None);
+    }
+
+    #[test]
+    fn it_should_continue_from_a_snapshot_like_a_single_run() {
+        let client = |tx: u32| (tx / 5 % 5) as u16;
+        let transactions = (10..2000)
+            .map(|tx| match tx % 5 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Chargeback, client(tx), tx - 3, 0.0),
+                _ => transaction(TransactionType::Dispute, client(tx - 9), tx - 9, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Transactions(5)),
+            ..ledger::Config::default()
+        };
+        let apply = |ledger: &mut ledger::Ledger, transactions: &[Transaction]| {
+            transactions
+                .iter()
+                .filter_map(|transaction| ledger.apply(transaction).err())
+                .collect::<Vec<TransactionError>>()
+        };
+
+        let mut combined = ledger::Ledger::new(config.clone());
+        let errors = apply(&mut combined, &transactions);
+
+        let path = write_input("snapshot", "");
+        [0, 1, 703, transactions.len()].iter().for_each(|split| {
+            let mut first = ledger::Ledger::new(config.clone());
+            let mut split_errors = apply(&mut first, &transactions[..*split]);
+            snapshot::write(
+                &path,
+                &snapshot::Snapshot {
+                    state: first.state(),
+                    input: None,
+                },
+            )
+            .unwrap();
+
+            let state = snapshot::read(&path).unwrap().unwrap().state;
+            assert_eq!(state, first.state());
+            let mut second = ledger::Ledger::from_state(config.clone(), state);
+            split_errors.append(&mut apply(&mut second, &transactions[*split..]));
+            assert_eq!(split_errors, errors);
+            assert_eq!(second.state(), combined.state());
+        });
+    }
+
+    #[test]
+    fn it_should_refuse_snapshots_it_cannot_read() {
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        ledger
+            .apply(&at(
+                transaction(TransactionType::Deposit, 3, 1, 2.5),
+                "2024-03-01T12:00:00Z",
+            ))
+            .unwrap();
+        let saved = snapshot::Snapshot {
+            state: ledger.state(),
+            input: Some(Checkpoint {
+                source: "/tmp/input.csv".into(),
+                offset: 42,
+                rows: 2,
+                fingerprint: 7,
+            }),
+        };
+        let bytes = snapshot::encode(&saved);
+        assert_eq!(snapshot::decode(&bytes).unwrap(), saved);
+
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(snapshot::VERSION + 1).to_le_bytes());
+        assert!(snapshot::decode(&newer).is_err());
+        assert!(snapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(snapshot::decode(&[bytes.clone(), vec![0]].concat()).is_err());
+        assert!(snapshot::decode(b"type,client,tx,amount\n").is_err());
+        assert_eq!(
+            snapshot::read(&format!("{}.missing", write_input("no_snapshot", ""))).unwrap(),
+            None
+        );
+    }
+
+    /*
+     * Runs the first `killed_at` transactions, writing a snapshot every 40 of
+     * them, and then just stops, like the process was killed. With `torn`, it
+     * died halfway through writing the last record, so that one never happened.
+     * Returns the errors of whatever was acknowledged, by position.
+     * */
+    fn run_until_killed(
+        transactions: &[Transaction],
+        config: &ledger::Config,
+        name: &str,
+        killed_at: usize,
+        torn: Option<u64>,
+    ) -> (String, String, Vec<(u64, TransactionError)>) {
+        let snapshot_path = write_input(&format!("{}_snapshot", name), "");
+        let log_path = write_input(&format!("{}_wal", name), "");
+        std::fs::remove_file(&snapshot_path).unwrap();
+        let mut durable = DurableLedger::open(&snapshot_path, &log_path, config.clone()).unwrap();
+        let mut errors = vec![];
+        transactions[..killed_at].iter().for_each(|transaction| {
+            let position = durable.position();
+            if let Err(e) = durable.apply(transaction, None).unwrap() {
+                errors.push((position, e));
+            }
+            if durable.position().is_multiple_of(40) {
+                durable.checkpoint().unwrap();
+            }
+        });
+        drop(durable);
+        if let Some(torn) = torn {
+            let length = std::fs::metadata(&log_path).unwrap().len();
+            let record = wal::encode(&wal::Entry {
+                position: 0,
+                transaction: transactions[0],
+                accepted: true,
+                account: None,
+                input: None,
+            })
+            .len() as u64;
+            if length > 12 {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&log_path)
+                    .unwrap()
+                    .set_len(length - record + torn % record)
+                    .unwrap();
+                errors.retain(|(position, _)| *position < killed_at as u64 - 1);
+            }
+        }
+        (snapshot_path, log_path, errors)
+    }
+
+    #[test]
+    fn it_should_recover_from_being_killed_at_any_point() {
+        let client = |tx: u32| (tx / 5 % 5) as u16;
+        let transactions = (10..300)
+            .map(|tx| match tx % 5 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Chargeback, client(tx), tx - 3, 0.0),
+                _ => transaction(TransactionType::Dispute, client(tx - 9), tx - 9, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let config = ledger::Config {
+            dispute_window: Some(ledger::DisputeWindow::Transactions(5)),
+            ..ledger::Config::default()
+        };
+        let mut combined = ledger::Ledger::new(config.clone());
+        let errors = transactions
+            .iter()
+            .filter_map(|transaction| combined.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+
+        (0..transactions.len()).step_by(7).for_each(|killed_at| {
+            [None, Some(killed_at as u64)].iter().for_each(|torn| {
+                let (snapshot_path, log_path, mut recovered_errors) =
+                    run_until_killed(&transactions, &config, "killed", killed_at, *torn);
+
+                let mut durable =
+                    DurableLedger::open(&snapshot_path, &log_path, config.clone()).unwrap();
+                let resumed = durable.position();
+                assert!(resumed <= killed_at as u64);
+                assert!(resumed + 1 >= killed_at as u64);
+                transactions[resumed as usize..]
+                    .iter()
+                    .for_each(|transaction| {
+                        let position = durable.position();
+                        if let Err(e) = durable.apply(transaction, None).unwrap() {
+                            recovered_errors.push((position, e));
+                        }
+                    });
+                let recovered_errors = recovered_errors
+                    .into_iter()
+                    .map(|(_, e)| e)
+                    .collect::<Vec<TransactionError>>();
+                assert_eq!(recovered_errors, errors);
+                assert_eq!(durable.ledger().state(), combined.state());
+            })
+        });
+    }
+
+    #[test]
+    fn it_should_checkpoint_once_the_log_is_full() {
+        let transactions = (0..1000)
+            .map(|tx| transaction(TransactionType::Deposit, (tx % 7) as u16, tx, 1.0))
+            .collect::<Vec<Transaction>>();
+        let snapshot_path = write_input("full_snapshot", "");
+        let log_path = write_input("full_wal", "");
+        std::fs::remove_file(&snapshot_path).unwrap();
+        let config = ledger::Config::default();
+        let mut durable = DurableLedger::open(&snapshot_path, &log_path, config.clone()).unwrap();
+        durable.set_log_limit(1000);
+        transactions.iter().for_each(|transaction| {
+            durable.apply(transaction, None).unwrap().unwrap();
+            assert!(std::fs::metadata(&log_path).unwrap().len() < 1000);
+        });
+        let (expected, _) = ledger::parse_transactions(&transactions);
+
+        /* Killed before the end, only what came after the last checkpoint is replayed */
+        drop(durable);
+        let durable = DurableLedger::open(&snapshot_path, &log_path, config).unwrap();
+        assert!(durable.replayed() > 0 && durable.replayed() < 20);
+        assert_eq!(durable.position(), 1000);
+        assert_eq!(durable.ledger().accounts(), expected);
+    }
+
+    #[test]
+    fn it_should_skip_what_the_snapshot_has_and_refuse_a_log_that_disagrees() {
+        let transactions = [
+            transaction(TransactionType::Deposit, 1, 1, 2.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 0.5),
+            transaction(TransactionType::Deposit, 2, 3, 1.0),
+        ];
+        let (snapshot_path, log_path, _) = run_until_killed(
+            &transactions,
+            &ledger::Config::default(),
+            "disagrees",
+            3,
+            None,
+        );
+        /* Killed after the snapshot was written, before the log was emptied */
+        let durable =
+            DurableLedger::open(&snapshot_path, &log_path, ledger::Config::default()).unwrap();
+        assert_eq!(durable.replayed(), 3);
+        snapshot::write(
+            &snapshot_path,
+            &snapshot::Snapshot {
+                state: durable.ledger().state(),
+                input: None,
+            },
+        )
+        .unwrap();
+        drop(durable);
+        let durable =
+            DurableLedger::open(&snapshot_path, &log_path, ledger::Config::default()).unwrap();
+        assert_eq!(durable.replayed(), 0);
+        assert_eq!(durable.position(), 3);
+
+        std::fs::remove_file(&snapshot_path).unwrap();
+        let config = ledger::Config {
+            zero_amount: ledger::ZeroAmountPolicy::Reject,
+            ..ledger::Config::default()
+        };
+        let (snapshot_path, log_path, _) = run_until_killed(
+            &[transaction(TransactionType::Deposit, 1, 1, 0.0)],
+            &ledger::Config::default(),
+            "disagrees",
+            1,
+            None,
+        );
+        assert!(DurableLedger::open(&snapshot_path, &log_path, config).is_err());
+    }
+
+    #[test]
+    fn it_should_stop_reading_the_log_at_a_torn_record_and_refuse_a_damaged_one() {
+        assert_eq!(wal::crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            wal::crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+        assert_eq!(wal::crc32(b""), 0);
+        let records = (0..3)
+            .map(|position| {
+                wal::encode(&wal::Entry {
+                    position,
+                    transaction: at(
+                        transaction(TransactionType::Deposit, 4, position as u32, 1.5),
+                        "2024-03-01T12:00:00Z",
+                    ),
+                    accepted: true,
+                    account: Some(account(4, 1.5, 0.0, false)),
+                    input: Some(Checkpoint {
+                        source: "/tmp/input.csv".into(),
+                        offset: 22 * (position + 1),
+                        rows: position + 1,
+                        fingerprint: 7,
+                    }),
+                })
+            })
+            .collect::<Vec<Vec<u8>>>();
+        let mut log = b"TPESWAL\0".to_vec();
+        log.extend_from_slice(&1u32.to_le_bytes());
+        records
+            .iter()
+            .for_each(|record| log.extend_from_slice(record));
+
+        let (entries, intact) = wal::decode(&log).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(intact, log.len() as u64);
+        assert_eq!(wal::encode(&entries[1]), records[1]);
+        (12..log.len()).for_each(|cut| {
+            let (entries, intact) = wal::decode(&log[..cut]).unwrap();
+            assert_eq!(entries.len(), (cut - 12) / records[0].len());
+            assert_eq!(intact, 12 + (entries.len() * records[0].len()) as u64);
+        });
+        /* Only the last record can be damaged by a write that didn't finish */
+        let mut damaged = log.clone();
+        damaged[12 + 2 * records[0].len() + 20] ^= 1;
+        assert_eq!(wal::decode(&damaged).unwrap().0.len(), 2);
+        let mut damaged = log.clone();
+        damaged[12 + records[0].len() + 20] ^= 1;
+        assert!(wal::decode(&damaged).is_err());
+        /* A record that checks out but can't be read isn't torn either */
+        let mut unreadable = records[2].clone();
+        unreadable[8 + 8] = 9;
+        let checksum = wal::crc32(&unreadable[8..]);
+        unreadable[4..8].copy_from_slice(&checksum.to_le_bytes());
+        let mut damaged = log[..12 + 2 * records[0].len()].to_vec();
+        damaged.extend_from_slice(&unreadable);
+        assert!(wal::decode(&damaged).is_err());
+        assert!(wal::decode(b"TPESWAL\0\x02\0\0\0").is_err());
+        assert!(wal::decode(b"type,client,tx,amount\n").is_err());
+    }
+
+    #[test]
+    fn it_should_only_read_rows_past_the_checkpoint() {
+        let path = write_input(
+            "checkpoint",
+            "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,1.0\nwithdrawal,1,3,0.5",
+        );
+        let first = checkpoint::read_remaining(&path, None).unwrap();
+        assert_eq!(first.skipped, 0);
+        assert_eq!(first.unfinished, "withdrawal,1,3,0.5".len());
+        assert_eq!(
+            first
+                .transactions
+                .iter()
+                .map(|(transaction, checkpoint)| (
+                    transaction.tx,
+                    checkpoint.offset,
+                    checkpoint.rows
+                ))
+                .collect::<Vec<(u32, u64, u64)>>(),
+            vec![(1, 38, 1), (2, 54, 2)]
+        );
+        assert!(std::sync::Arc::ptr_eq(
+            &first.transactions[0].1.source,
+            &first.transactions[1].1.source
+        ));
+
+        /* The row that was still being written turns out to be for 0.55 */
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"5\ndeposit,2,4,3.0\n").unwrap();
+        let last = &first.transactions[1].1;
+        let second = checkpoint::read_remaining(&path, Some(last)).unwrap();
+        assert_eq!(second.skipped, 2);
+        assert_eq!(second.unfinished, 0);
+        assert_eq!(
+            second
+                .transactions
+                .iter()
+                .map(|(transaction, _)| *transaction)
+                .collect::<Vec<Transaction>>(),
+            vec![
+                transaction(TransactionType::Withdrawal, 1, 3, 0.55),
+                transaction(TransactionType::Deposit, 2, 4, 3.0),
+            ]
+        );
+        let end = &second.transactions[1].1;
+        assert!(checkpoint::read_remaining(&path, Some(end))
+            .unwrap()
+            .transactions
+            .is_empty());
+
+        let elsewhere = Checkpoint {
+            source: "/somewhere/else.csv".into(),
+            ..end.clone()
+        };
+        assert_eq!(
+            checkpoint::read_remaining(&path, Some(&elsewhere))
+                .unwrap()
+                .transactions
+                .len(),
+            4
+        );
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,7,1,2.0\n").unwrap();
+        assert!(checkpoint::read_remaining(&path, Some(last)).is_err());
+    }
+
+    #[test]
+    fn it_should_apply_every_row_once_when_killed_and_run_again() {
+        let input = generated_input(300);
+        let path = write_input("exactly_once", &input);
+        let (accounts, _) =
+            ledger::parse_transactions(&reader::read_file_from_path(&path).unwrap());
+        let snapshot_path = write_input("exactly_once_snapshot", "");
+        let log_path = write_input("exactly_once_wal", "");
+
+        (0..300).step_by(13).for_each(|killed_at| {
+            std::fs::remove_file(&snapshot_path).ok();
+            std::fs::write(&log_path, "").unwrap();
+            let mut durable =
+                DurableLedger::open(&snapshot_path, &log_path, ledger::Config::default()).unwrap();
+            checkpoint::read_remaining(&path, durable.input())
+                .unwrap()
+                .transactions
+                .into_iter()
+                .take(killed_at)
+                .enumerate()
+                .for_each(|(row, (transaction, checkpoint))| {
+                    durable.apply(&transaction, Some(checkpoint)).unwrap().ok();
+                    if row == 100 {
+                        durable.checkpoint().unwrap();
+                    }
+                });
+            drop(durable);
+            /* Died halfway through the last record */
+            let length = std::fs::metadata(&log_path).unwrap().len();
+            if length > 12 {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&log_path)
+                    .unwrap()
+                    .set_len(length - 1)
+                    .unwrap();
+            }
+
+            let mut durable =
+                DurableLedger::open(&snapshot_path, &log_path, ledger::Config::default()).unwrap();
+            let remaining = checkpoint::read_remaining(&path, durable.input()).unwrap();
+            assert_eq!(remaining.skipped + remaining.transactions.len() as u64, 300);
+            remaining
+                .transactions
+                .into_iter()
+                .for_each(|(transaction, checkpoint)| {
+                    durable.apply(&transaction, Some(checkpoint)).unwrap().ok();
+                });
+            durable.checkpoint().unwrap();
+            assert_eq!(durable.ledger().accounts(), sorted(accounts.clone()));
+
+            let durable =
+                DurableLedger::open(&snapshot_path, &log_path, ledger::Config::default()).unwrap();
+            assert!(checkpoint::read_remaining(&path, durable.input())
+                .unwrap()
+                .transactions
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn it_should_emit_events_for_what_happened_to_accounts() {
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let happened = [
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Deposit, 1, 2, 2.0),
+            transaction(TransactionType::Withdrawal, 1, 3, 1.5),
+            transaction(TransactionType::Withdrawal, 1, 4, 10.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Resolve, 1, 1, 0.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Chargeback, 1, 1, 0.0),
+        ]
+        .iter()
+        .flat_map(|transaction| events::apply(&mut ledger, transaction).1)
+        .collect::<Vec<Event>>();
+        let five = from_float(5.0);
+        assert_eq!(
+            happened,
+            vec![
+                Event::AccountOpened { client: 1 },
+                Event::FundsDeposited {
+                    client: 1,
+                    tx: 1,
+                    amount: five
+                },
+                Event::FundsDeposited {
+                    client: 1,
+                    tx: 2,
+                    amount: from_float(2.0)
+                },
+                Event::FundsWithdrawn {
+                    client: 1,
+                    tx: 3,
+                    amount: from_float(1.5)
+                },
+                Event::FundsHeld {
+                    client: 1,
+                    tx: 1,
+                    amount: five
+                },
+                Event::FundsReleased {
+                    client: 1,
+                    tx: 1,
+                    amount: five
+                },
+                Event::FundsHeld {
+                    client: 1,
+                    tx: 1,
+                    amount: five
+                },
+                Event::ChargedBack {
+                    client: 1,
+                    tx: 1,
+                    amount: five
+                },
+                Event::AccountLocked { client: 1 },
+            ]
+        );
+        assert_eq!(
+            events::rebuild(&happened, &BTreeMap::new()).unwrap(),
+            ledger.accounts()
+        );
+
+        assert!(events::rebuild(&happened[1..], &BTreeMap::new()).is_err());
+        assert!(events::rebuild(
+            &[
+                Event::AccountOpened { client: 2 },
+                Event::FundsWithdrawn {
+                    client: 2,
+                    tx: 1,
+                    amount: five
+                },
+            ],
+            &BTreeMap::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_should_rebuild_the_accounts_from_the_event_log() {
+        let client = |tx: u32| (tx / 6 % 5) as u16;
+        let transactions = (12..600)
+            .map(|tx| match tx % 6 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Resolve, client(tx), tx - 3, 0.0),
+                4 => transaction(TransactionType::Dispute, client(tx), tx - 4, 0.0),
+                _ => transaction(TransactionType::Chargeback, client(tx), tx - 5, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let (accounts, _) = ledger::parse_transactions(&transactions);
+
+        /* Run twice, the second run starts the log over */
+        let path = write_input("events", "");
+        std::fs::remove_file(&path).unwrap();
+        (0..2).for_each(|_| {
+            events::truncate(&path).unwrap();
+            let mut ledger = ledger::Ledger::new(ledger::Config::default());
+            transactions.chunks(100).for_each(|chunk| {
+                let happened = chunk
+                    .iter()
+                    .flat_map(|transaction| events::apply(&mut ledger, transaction).1)
+                    .collect::<Vec<Event>>();
+                events::append(&path, &happened).unwrap();
+            });
+            let happened = events::read(&path).unwrap();
+            assert!(happened.contains(&Event::AccountLocked { client: 3 }));
+            assert_eq!(
+                events::rebuild(&happened, &BTreeMap::new()).unwrap(),
+                accounts
+            );
+        });
+    }
+
+    #[test]
+    fn it_should_keep_a_balanced_journal_next_to_the_ledger() {
+        let client = |tx: u32| (tx / 6 % 5) as u16;
+        let transactions = (12..600)
+            .map(|tx| match tx % 6 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Resolve, client(tx), tx - 3, 0.0),
+                4 => transaction(TransactionType::Dispute, client(tx), tx - 4, 0.0),
+                _ => transaction(TransactionType::Chargeback, client(tx), tx - 5, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let (accounts, errors) = ledger::parse_transactions(&transactions);
+
+        let mut journaling = JournalingLedger::new(ledger::Ledger::new(ledger::Config::default()));
+        let journaled_errors = transactions
+            .iter()
+            .filter_map(|transaction| journaling.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+        assert_eq!(journaled_errors, errors);
+        assert_eq!(journaling.accounts(), accounts);
+        assert_eq!(
+            journaling.journal().entries(),
+            (transactions.len() - errors.len()) as u64
+        );
+
+        let trial_balance = journaling.journal().trial_balance();
+        assert!(trial_balance.is_balanced());
+        let balance = |book: Book| {
+            trial_balance
+                .rows
+                .iter()
+                .find(|row| row.book == book)
+                .map_or(Currency(0), |row| row.balance)
+        };
+        accounts.iter().for_each(|account| {
+            assert_eq!(
+                balance(Book::Available(account.client())).0,
+                -account.available().0
+            );
+            assert_eq!(balance(Book::Held(account.client())).0, -account.held().0);
+        });
+        let totals: i64 = accounts.iter().map(|account| account.total().0).sum();
+        assert_eq!(
+            balance(Book::Funding).0 + balance(Book::ChargebackLoss).0,
+            totals
+        );
+        assert!(balance(Book::ChargebackLoss).0 < 0);
+
+        let mut written = vec![];
+        journal::write_trial_balance(&trial_balance, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with("book,debits,credits,balance\navailable:0,"));
+        assert!(written.ends_with(",0.0\n"));
+    }
+
+    #[test]
+    fn it_should_refuse_journal_entries_that_do_not_balance() {
+        let deposit = transaction(TransactionType::Deposit, 1, 1, 2.0);
+        let after = account(1, 2.0, 0.0, false);
+        let mut journal = Journal::new();
+        let balanced = journal::entry_for(&deposit, None, after);
+        assert!(balanced.is_balanced());
+        journal.post(&balanced).unwrap();
+        let before = journal.trial_balance();
+
+        /* Part of the deposit went straight to held */
+        let skewed = journal::entry_for(&deposit, None, account(1, 2.0, 1.0, false));
+        assert!(!skewed.is_balanced());
+        assert_eq!(journal.post(&skewed), Err(skewed));
+        assert_eq!(journal.trial_balance(), before);
+        assert_eq!(journal.entries(), 1);
+    }
+
+    #[test]
+    fn it_should_find_no_invariant_violations_in_a_sound_ledger() {
+        let client = |tx: u32| (tx / 6 % 5) as u16;
+        let transactions = (12..600)
+            .map(|tx| match tx % 6 {
+                0 => transaction(TransactionType::Deposit, client(tx), tx, 2.0),
+                1 => transaction(TransactionType::Withdrawal, client(tx), tx, 1.0),
+                2 => transaction(TransactionType::Dispute, client(tx), tx - 2, 0.0),
+                3 => transaction(TransactionType::Resolve, client(tx), tx - 3, 0.0),
+                4 => transaction(TransactionType::Dispute, client(tx), tx - 4, 0.0),
+                _ => transaction(TransactionType::Chargeback, client(tx), tx - 5, 0.0),
+            })
+            .collect::<Vec<Transaction>>();
+        let (accounts, errors) = ledger::parse_transactions(&transactions);
+
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let mut checker = Checker::new();
+        let checked_errors = transactions
+            .iter()
+            .filter_map(|transaction| checker.apply(&mut ledger, transaction).err())
+            .collect::<Vec<TransactionError>>();
+        assert_eq!(checked_errors, errors);
+        assert_eq!(ledger.accounts(), accounts);
+        assert!(accounts.iter().any(|account| account.locked()));
+        assert_eq!(checker.take_violations(), vec![]);
+        assert_eq!(checker.check(&ledger), vec![]);
+    }
+
+    #[test]
+    fn it_should_report_accounts_that_break_the_invariants() {
+        let deposit = transaction(TransactionType::Deposit, 1, 1, 2.0);
+        let mut ledger = ledger::Ledger::new(ledger::Config::default());
+        let mut checker = Checker::new();
+        checker.apply(&mut ledger, &deposit).unwrap();
+        assert_eq!(checker.take_violations(), vec![]);
+
+        /* Available can go below 0, held can't */
+        let spent = [
+            transaction(TransactionType::Withdrawal, 1, 2, 1.5),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+        ];
+        spent.iter().for_each(|transaction| {
+            checker.apply(&mut ledger, transaction).unwrap();
+        });
+        assert_eq!(checker.take_violations(), vec![]);
+        let disputed = ledger.account(1).unwrap();
+        assert_eq!(disputed, account(1, -1.5, 2.0, false));
+        assert_eq!(invariants::check_account(&disputed, Some(spent[1])), vec![]);
+
+        /* As if a bug made 0.5 out of nothing along the way */
+        ledger.restore_accounts(vec![account(1, 1.0, 0.0, false)]);
+        assert_eq!(
+            checker.check(&ledger),
+            vec![Violation::SystemTotalMismatch(
+                None,
+                from_float(0.5),
+                from_float(1.0)
+            )]
+        );
+    }
+
+    #[test]
+    fn it_should_keep_accounts_adding_up() {
+        let mut account = account::new_from_client_id(1);
+        account.credit(from_float(2.0));
+        account.hold(from_float(3.0));
+        assert_eq!(account, self::account(1, -1.0, 3.0, false));
+        assert_eq!(account.total(), from_float(2.0));
+
+        /* Whatever fails leaves the account alone */
+        let before = account;
+        assert_eq!(
+            account.debit(from_float(0.5), Currency(0)),
+            Err(UnsettledReason::InsufficientFunds)
+        );
+        assert_eq!(
+            account.release(from_float(4.0)),
+            Err(UnsettledReason::InsufficientFunds)
+        );
+        assert_eq!(
+            account.reverse(from_float(3.0), Currency(0)),
+            Err(UnsettledReason::InsufficientFunds)
+        );
+        assert_eq!(account, before);
+
+        account.release(from_float(1.0)).unwrap();
+        account.reverse(from_float(2.0), Currency(0)).unwrap();
+        assert_eq!(account, self::account(1, 0.0, 0.0, true));
+
+        assert_eq!(
+            account::restore(1, from_float(1.0), from_float(-1.0), Currency(0), false),
+            None
+        );
+        let read = |csv: &str| {
+            csv::Reader::from_reader(csv.as_bytes())
+                .deserialize::<Account>()
+                .collect::<Result<Vec<Account>, csv::Error>>()
+        };
+        assert_eq!(
+            read("client,available,held,total,locked\n1,1.0,2.0,3.0,false\n").unwrap(),
+            vec![self::account(1, 1.0, 2.0, false)]
+        );
+        assert!(read("client,available,held,total,locked\n1,1.0,2.0,2.0,false\n").is_err());
+    }
+
+    #[test]
+    fn it_should_apply_the_overdraft_policy_to_disputes() {
+        let spent = vec![
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 3.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+        ];
+        let with = |overdraft: ledger::OverdraftPolicy, transactions: &[Transaction]| {
+            ledger::parse_transactions_with_config(
+                transactions,
+                ledger::Config {
+                    overdraft,
+                    ..ledger::Config::default()
+                },
+            )
+        };
+
+        let (accounts, errors) = with(ledger::OverdraftPolicy::Allow, &spent);
+        assert_eq!(accounts, vec![account(1, -3.0, 5.0, false)]);
+        assert_eq!(errors, vec![]);
+
+        let (accounts, errors) = with(ledger::OverdraftPolicy::Reject, &spent);
+        assert_eq!(accounts, vec![account(1, 2.0, 0.0, false)]);
+        assert_eq!(
+            errors,
+            vec![TransactionError::UnsettledDispute(
+                spent[2],
+                UnsettledReason::InsufficientFunds
+            )]
+        );
+
+        let (accounts, errors) = with(ledger::OverdraftPolicy::Receivable, &spent);
+        assert_eq!(errors, vec![]);
+        assert_eq!(accounts[0].available(), from_float(0.0));
+        assert_eq!(accounts[0].held(), from_float(2.0));
+        assert_eq!(accounts[0].receivable(), from_float(3.0));
+
+        /* A resolve puts it all back, a chargeback leaves the client owing */
+        let resolved = [
+            spent.clone(),
+            vec![transaction(TransactionType::Resolve, 1, 1, 0.0)],
+        ]
+        .concat();
+        let (accounts, _) = with(ledger::OverdraftPolicy::Receivable, &resolved);
+        assert_eq!(accounts, vec![account(1, 2.0, 0.0, false)]);
+        let charged_back = [
+            spent.clone(),
+            vec![transaction(TransactionType::Chargeback, 1, 1, 0.0)],
+        ]
+        .concat();
+        let (accounts, errors) = with(ledger::OverdraftPolicy::Receivable, &charged_back);
+        assert_eq!(errors, vec![]);
+        assert_eq!(accounts[0].total(), from_float(0.0));
+        assert_eq!(accounts[0].receivable(), from_float(3.0));
+        assert!(accounts[0].locked());
+
+        let mut written = vec![];
+        writer::write_accounts(
+            &accounts,
+            &[writer::Column::Receivable],
+            &ledger::Config::default(),
+            &mut written,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "client,available,held,total,locked,receivable\n1,0.0,0.0,0.0,true,3.0\n"
+        );
+        let mut written = vec![];
+        writer::write_accounts(&accounts, &[], &ledger::Config::default(), &mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,true\n"
+        );
+
+        /* The shortfall makes it through a snapshot, so a later resolve still adds up */
+        let config = ledger::Config {
+            overdraft: ledger::OverdraftPolicy::Receivable,
+            ..ledger::Config::default()
+        };
+        let mut ledger = ledger::Ledger::new(config.clone());
+        spent
+            .iter()
+            .for_each(|transaction| ledger.apply(transaction).unwrap());
+        let saved = snapshot::Snapshot {
+            state: ledger.state(),
+            input: None,
+        };
+        assert_eq!(saved.state.shortfalls, vec![(1, from_float(3.0))]);
+        let restored = snapshot::decode(&snapshot::encode(&saved)).unwrap();
+        assert_eq!(restored, saved);
+        let mut ledger = ledger::Ledger::from_state(config, restored.state);
+        ledger
+            .apply(&transaction(TransactionType::Resolve, 1, 1, 0.0))
+            .unwrap();
+        assert_eq!(ledger.accounts(), vec![account(1, 2.0, 0.0, false)]);
+        assert_eq!(ledger.state().shortfalls, vec![]);
+    }
+
+    #[test]
+    fn it_should_let_clients_draw_on_their_credit_line() {
+        let path = write_input("clients", "client,credit_limit\n1,2.0\n3,\n");
+        let clients = clients::read(&path).unwrap();
+        assert_eq!(clients[&1].credit_limit, from_float(2.0));
+        assert_eq!(clients[&3].credit_limit, from_float(0.0));
+        assert!(clients::read(&write_input(
+            "clients_twice",
+            "client,credit_limit\n1,2.0\n1,3.0\n"
+        ))
+        .is_err());
+        assert!(clients::read(&write_input(
+            "clients_negative",
+            "client,credit_limit\n1,-2.0\n"
+        ))
+        .is_err());
+
+        let config = ledger::Config {
+            clients,
+            ..ledger::Config::default()
+        };
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, 1, 5.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 6.5),
+            /* Would take it to -2.5 */
+            transaction(TransactionType::Withdrawal, 1, 3, 1.0),
+            transaction(TransactionType::Withdrawal, 1, 4, 0.5),
+            transaction(TransactionType::Deposit, 2, 5, 1.0),
+            transaction(TransactionType::Withdrawal, 2, 6, 1.5),
+        ];
+        let (accounts, errors) =
+            ledger::parse_transactions_with_config(&transactions, config.clone());
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::UnsettledWithdrawal(
+                    transactions[2],
+                    UnsettledReason::InsufficientFunds
+                ),
+                TransactionError::UnsettledWithdrawal(
+                    transactions[5],
+                    UnsettledReason::InsufficientFunds
+                ),
+            ]
+        );
+        assert_eq!(accounts[0], account(1, -2.0, 0.0, false));
+        assert_eq!(accounts[0].credit_used(), from_float(2.0));
+
+        /* Deposits pay off what's drawn first */
+        let repaid = [
+            transactions.clone(),
+            vec![transaction(TransactionType::Deposit, 1, 7, 3.0)],
+        ]
+        .concat();
+        let (accounts, _) = ledger::parse_transactions_with_config(&repaid, config.clone());
+        assert_eq!(accounts[0], account(1, 1.0, 0.0, false));
+        assert_eq!(accounts[0].credit_used(), from_float(0.0));
+
+        let mut written = vec![];
+        writer::write_accounts(&accounts, &writer::columns(&config), &config, &mut written)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "client,available,held,total,locked,credit_limit,credit_used\n\
+             1,1.0,0.0,1.0,false,2.0,0.0\n\
+             2,1.0,0.0,1.0,false,0.0,0.0\n"
+        );
+
+        /* A deposit that was spent on credit can still be charged back */
+        let config = ledger::Config {
+            clients: BTreeMap::from([(
+                1,
+                clients::ClientConfig {
+                    credit_limit: from_float(100.0),
+                    ..clients::ClientConfig::default()
+                },
+            )]),
+            ..ledger::Config::default()
+        };
+        let charged_back = [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 50.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Chargeback, 1, 1, 0.0),
+        ];
+        let (accounts, errors) =
+            ledger::parse_transactions_with_config(&charged_back, config.clone());
+        assert_eq!(errors, vec![]);
+        assert_eq!(accounts, vec![account(1, -50.0, 0.0, true)]);
+        /* But not for more than the credit line */
+        let (_, errors) = ledger::parse_transactions_with_config(
+            &[
+                transaction(TransactionType::Deposit, 1, 1, 10.0),
+                transaction(TransactionType::Withdrawal, 1, 2, 105.0),
+                transaction(TransactionType::Dispute, 1, 1, 0.0),
+                transaction(TransactionType::Chargeback, 1, 1, 0.0),
+            ],
+            config,
+        );
+        assert_eq!(
+            errors,
+            vec![TransactionError::UnsettledChargeback(
+                transaction(TransactionType::Chargeback, 1, 1, 0.0),
+                UnsettledReason::InsufficientFunds
+            )]
+        );
+    }
+
+    #[test]
+    fn it_should_hold_withdrawals_to_their_limits() {
+        let clients = clients::read(&write_input(
+            "clients_limits",
+            "client,credit_limit,max_withdrawal,max_withdrawal_count,max_withdrawal_total\n\
+             2,,,,3.0\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            clients[&2].withdrawal_limits,
+            WithdrawalLimits {
+                max_amount: None,
+                max_count: None,
+                max_total: Some(from_float(3.0)),
+            }
+        );
+        assert!(clients::read(&write_input(
+            "clients_negative_limit",
+            "client,max_withdrawal\n1,-1.0\n"
+        ))
+        .is_err());
+
+        let config = ledger::Config {
+            clients,
+            withdrawal_limits: WithdrawalLimits {
+                max_amount: Some(from_float(5.0)),
+                max_count: Some(2),
+                max_total: None,
+            },
+            withdrawal_window: Some(ledger::DisputeWindow::Transactions(3)),
+            ..ledger::Config::default()
+        };
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, 1, 100.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 6.0),
+            transaction(TransactionType::Withdrawal, 1, 3, 1.0),
+            transaction(TransactionType::Withdrawal, 1, 4, 1.0),
+            /* The two before it are still in the window */
+            transaction(TransactionType::Withdrawal, 1, 5, 1.0),
+            /* Only one of them is now */
+            transaction(TransactionType::Withdrawal, 1, 6, 1.0),
+            /* Client 2 has its own total, and everybody's amount and count */
+            transaction(TransactionType::Deposit, 2, 7, 10.0),
+            transaction(TransactionType::Withdrawal, 2, 8, 2.0),
+            transaction(TransactionType::Withdrawal, 2, 9, 2.0),
+        ];
+        let (accounts, errors) =
+            ledger::parse_transactions_with_config(&transactions, config.clone());
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::WithdrawalLimit(transactions[1], Limit::Amount(from_float(5.0))),
+                TransactionError::WithdrawalLimit(transactions[4], Limit::Count(2)),
+                TransactionError::WithdrawalLimit(transactions[8], Limit::Total(from_float(3.0))),
+            ]
+        );
+        assert_eq!(
+            accounts,
+            vec![account(1, 97.0, 0.0, false), account(2, 8.0, 0.0, false)]
+        );
+
+        /* What's in the window makes it through a snapshot */
+        let mut ledger = ledger::Ledger::new(config.clone());
+        transactions[..6].iter().for_each(|transaction| {
+            let _ = ledger.apply(transaction);
+        });
+        let saved = snapshot::Snapshot {
+            state: ledger.state(),
+            input: None,
+        };
+        let restored = snapshot::decode(&snapshot::encode(&saved)).unwrap();
+        assert_eq!(restored, saved);
+        let mut ledger = ledger::Ledger::from_state(config, restored.state);
+        ledger
+            .apply(&transaction(TransactionType::Withdrawal, 1, 10, 1.0))
+            .unwrap();
+        assert_eq!(
+            ledger.apply(&transaction(TransactionType::Withdrawal, 1, 11, 1.0)),
+            Err(TransactionError::WithdrawalLimit(
+                transaction(TransactionType::Withdrawal, 1, 11, 1.0),
+                Limit::Count(2)
+            ))
+        );
+
+        /* Without a window only how many and how much is kept, and restored */
+        let config = ledger::Config {
+            withdrawal_limits: WithdrawalLimits {
+                max_amount: None,
+                max_count: Some(3),
+                max_total: Some(from_float(10.0)),
+            },
+            ..ledger::Config::default()
+        };
+        let mut ledger = ledger::Ledger::new(config.clone());
+        transactions[..4].iter().for_each(|transaction| {
+            let _ = ledger.apply(transaction);
+        });
+        let state = ledger.state();
+        assert!(state.withdrawals.is_empty());
+        assert_eq!(state.withdrawn, vec![(1, 3, from_float(8.0))]);
+        let mut ledger = ledger::Ledger::from_state(config.clone(), state);
+        assert_eq!(
+            ledger.apply(&transactions[4]),
+            Err(TransactionError::WithdrawalLimit(
+                transactions[4],
+                Limit::Count(3)
+            ))
+        );
+
+        /* A window too big to add to doesn't let anything out of it */
+        let config = ledger::Config {
+            withdrawal_window: Some(ledger::DisputeWindow::Transactions(u64::MAX)),
+            ..config
+        };
+        let (_, errors) = ledger::parse_transactions_with_config(&transactions[..5], config);
+        assert_eq!(
+            errors,
+            vec![TransactionError::WithdrawalLimit(
+                transactions[4],
+                Limit::Count(3)
+            )]
+        );
+        assert!(config::window("18446744073709551615d").is_err());
+        assert!(config::window("18446744073709551615").is_ok());
+    }
+
+    /* Stands in for whatever rule someone might want to plug in */
+    struct NoWithdrawalsWhileHeld;
+
+    impl Rule for NoWithdrawalsWhileHeld {
+        fn name(&self) -> &str {
+            "no-withdrawals-while-held"
+        }
+
+        fn check(
+            &mut self,
+            transaction: &Transaction,
+            account: Option<&Account>,
+            _: ledger::Clock,
+        ) -> Verdict {
+            match (transaction.tx_type, account) {
+                (TransactionType::Withdrawal, Some(account)) if account.held().0 > 0 => {
+                    Verdict::Deny("something is disputed".to_string())
+                }
+                _ => Verdict::Allow,
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_run_transactions_by_the_rules() {
+        let config = ledger::Config {
+            rules: rules::Config {
+                large_deposit: Some(from_float(100.0)),
+                rapid_withdrawal: Some(ledger::DisputeWindow::Transactions(2)),
+                max_disputes: Some(1),
+            },
+            ..ledger::Config::default()
+        };
+        let transactions = [
+            transaction(TransactionType::Deposit, 1, 1, 150.0),
+            transaction(TransactionType::Deposit, 1, 2, 10.0),
+            /* Right after the deposit before it */
+            transaction(TransactionType::Withdrawal, 1, 3, 5.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            /* Denied, so it's not flagged either */
+            transaction(TransactionType::Withdrawal, 1, 4, 5.0),
+            transaction(TransactionType::Resolve, 1, 2, 0.0),
+            transaction(TransactionType::Withdrawal, 1, 5, 5.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+        ];
+        let mut ledger = ledger::Ledger::new(config.clone());
+        ledger.add_rule(Box::new(NoWithdrawalsWhileHeld));
+        let errors = transactions
+            .iter()
+            .filter_map(|transaction| ledger.apply(transaction).err())
+            .collect::<Vec<TransactionError>>();
+        assert_eq!(
+            errors,
+            vec![TransactionError::Denied(
+                transactions[4],
+                "no-withdrawals-while-held".to_string(),
+                "something is disputed".to_string()
+            )]
+        );
+        assert_eq!(ledger.accounts(), vec![account(1, 140.0, 10.0, false)]);
+        let flagged = ledger.take_warnings();
+        assert_eq!(
+            flagged
+                .iter()
+                .map(|flag| match flag {
+                    TransactionError::Flagged(transaction, rule, _) =>
+                        (transaction.tx, rule.as_str()),
+                    other => panic!("{:?} isn't flagged", other),
+                })
+                .collect::<Vec<(u32, &str)>>(),
+            vec![
+                (1, "large-deposit"),
+                (3, "rapid-withdrawal"),
+                (2, "repeated-disputes")
+            ]
+        );
+
+        let mut written = vec![];
+        writer::write_review(&flagged[..1], &mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "type,client,tx,amount,rule,reason\n\
+             deposit,1,1,150.0,large-deposit,deposit over the threshold\n"
+        );
+        let mut written = vec![];
+        writer::write_review(&[], &mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "type,client,tx,amount,rule,reason\n"
+        );
+
+        /* What the rules remember makes it through a snapshot */
+        let saved = snapshot::Snapshot {
+            state: ledger.state(),
+            input: None,
+        };
+        let restored = snapshot::decode(&snapshot::encode(&saved)).unwrap();
+        assert_eq!(restored, saved);
+        let mut ledger = ledger::Ledger::from_state(config, restored.state);
+        let again = [
+            transaction(TransactionType::Resolve, 1, 2, 0.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+        ];
+        again
+            .iter()
+            .for_each(|transaction| ledger.apply(transaction).unwrap());
+        assert_eq!(
+            ledger
+                .take_warnings()
+                .iter()
+                .map(|flag| match flag {
+                    TransactionError::Flagged(transaction, rule, _) =>
+                        (transaction.tx, rule.as_str()),
+                    other => panic!("{:?} isn't flagged", other),
+                })
+                .collect::<Vec<(u32, &str)>>(),
+            vec![(1, "repeated-disputes")]
+        );
+
+        /* A window in time only goes by rows that have a timestamp */
+        let config = ledger::Config {
+            rules: rules::Config {
+                rapid_withdrawal: Some(ledger::DisputeWindow::Time(
+                    std::time::Duration::from_secs(60),
+                )),
+                ..rules::Config::default()
+            },
+            ..ledger::Config::default()
+        };
+        let transactions = [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 1.0),
+            transaction(TransactionType::Withdrawal, 1, 3, 1.0),
+            at(
+                transaction(TransactionType::Deposit, 1, 4, 10.0),
+                "2024-03-01T12:00:00Z",
+            ),
+            transaction(TransactionType::Withdrawal, 1, 5, 1.0),
+            at(
+                transaction(TransactionType::Withdrawal, 1, 6, 1.0),
+                "2024-03-01T12:00:30Z",
+            ),
+            at(
+                transaction(TransactionType::Withdrawal, 1, 7, 1.0),
+                "2024-03-01T12:01:00Z",
+            ),
+        ];
+        let mut ledger = ledger::Ledger::new(config);
+        transactions
+            .iter()
+            .for_each(|transaction| ledger.apply(transaction).unwrap());
+        assert_eq!(
+            ledger
+                .take_warnings()
+                .iter()
+                .map(|flag| match flag {
+                    TransactionError::Flagged(transaction, ..) => transaction.tx,
+                    other => panic!("{:?} isn't flagged", other),
+                })
+                .collect::<Vec<u32>>(),
+            vec![6]
+        );
+    }
 }
@@ -0,0 +1,161 @@
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+use std::fmt;
+
+/*
+ * Milliseconds since the Unix epoch, in UTC.
+ *
+ * There's exactly one format we accept: RFC 3339 in UTC, with an optional
+ * fraction of up to 3 digits, like `2024-03-01T12:30:00Z` or
+ * `2024-03-01T12:30:00.250Z`. No offsets, no local time, no leap seconds. Two
+ * rows with the same timestamp text always mean the same moment, and comparing
+ * timestamps is comparing numbers.
+ * */
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash)]
+pub struct Timestamp(pub u64);
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/*
+ * Days since 1970-01-01 of a date in the proleptic Gregorian calendar, and the
+ * other way around. See http://howardhinnant.github.io/date_algorithms.html
+ * */
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub fn parse(text: &str) -> Result<Timestamp, String> {
+    let invalid = || {
+        format!(
+            "invalid timestamp `{}`, expected e.g. 2024-03-01T12:30:00Z",
+            text
+        )
+    };
+    let bytes = text.as_bytes();
+    if !text.is_ascii()
+        || bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || bytes[10] != b'T'
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+        || bytes[bytes.len() - 1] != b'Z'
+    {
+        return Err(invalid());
+    }
+    let number = |range: std::ops::Range<usize>| {
+        let digits = &text[range];
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse::<u64>().map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    let millis = match &text[19..text.len() - 1] {
+        "" => 0,
+        fraction => match fraction.strip_prefix('.') {
+            Some(digits) if (1..=3).contains(&digits.len()) => {
+                number(20..20 + digits.len())? * 10u64.pow(3 - digits.len() as u32)
+            }
+            _ => return Err(invalid()),
+        },
+    };
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+    Ok(Timestamp(
+        days_from_civil(year, month, day) * MILLIS_PER_DAY
+            + ((hour * 60 + minute) * 60 + second) * 1000
+            + millis,
+    ))
+}
+
+/* Writes the timestamp back in the same format it's read in */
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0 / MILLIS_PER_DAY);
+        let millis = self.0 % MILLIS_PER_DAY;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60
+        )?;
+        match millis % 1000 {
+            0 => write!(f, "Z"),
+            fraction => write!(f, ".{:03}Z", fraction),
+        }
+    }
+}
+
+/*
+ * The timestamp column is optional: a missing column or an empty field both
+ * mean there's no timestamp.
+ * */
+pub fn from_timestamp_string<'de, D>(deserializer: D) -> Result<Option<Timestamp>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Deserialize::deserialize(deserializer)? {
+        "" => Ok(None),
+        s => parse(s).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/* No timestamp is written as an empty field */
+pub fn to_timestamp_string<S>(timestamp: &Option<Timestamp>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match timestamp {
+        Some(timestamp) => s.collect_str(timestamp),
+        None => s.serialize_str(""),
+    }
+}
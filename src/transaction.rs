@@ -1,7 +1,8 @@
-use crate::currency::{from_float_string, Currency};
-use serde::Deserialize;
+use crate::currency::{from_float_string, to_float_string, Currency};
+use crate::timestamp::{from_timestamp_string, to_timestamp_string, Timestamp};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -21,13 +22,23 @@ pub enum TransactionType {
  * Unfortunately, tagged unions don't deserialize properly when coming in from
  * CSV / writing a custom deserializer for this is a bit overkill for now.
  * */
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
     pub client: u16,
     pub tx: u32,
     /* If this is empty string, or null, it will serialize to 0. */
-    #[serde(deserialize_with = "from_float_string")]
+    #[serde(
+        serialize_with = "to_float_string",
+        deserialize_with = "from_float_string"
+    )]
     pub amount: Currency,
+    /* Optional, as a column and per row. See `Timestamp` for the format. */
+    #[serde(
+        default,
+        serialize_with = "to_timestamp_string",
+        deserialize_with = "from_timestamp_string"
+    )]
+    pub timestamp: Option<Timestamp>,
 }
@@ -0,0 +1,90 @@
+use crate::error::{Csv, Validation};
+use crate::reader::{self, Row};
+use crate::transaction::TransactionType;
+use std::collections::{HashMap, HashSet};
+
+/*
+ * Checks an input file without touching any account. We look at every row on
+ * its own, and only keep the tx ids of deposits and withdrawals around, to
+ * find duplicates. Deposits that can go through are kept apart with the
+ * client they're for, so a dispute / resolve / chargeback can be checked
+ * against a deposit of the same client that came before it. Anything else is
+ * a transaction the ledger won't find either. Plus the latest timestamp so
+ * far, to find rows that are out of order.
+ * */
+pub fn validate_rows(rows: &[Row]) -> Vec<Validation> {
+    let mut seen: HashSet<u32> = HashSet::new();
+    let mut deposits: HashMap<u32, u16> = HashMap::new();
+    let mut latest = None;
+    let mut problems: Vec<Validation> = vec![];
+
+    rows.iter().for_each(|row| match &row.transaction {
+        Err(e) => problems.push(Validation::MalformedRow(row.line, e.clone())),
+        Ok(transaction) => {
+            if transaction.timestamp.is_some() && transaction.timestamp < latest {
+                problems.push(Validation::OutOfOrder(row.line, *transaction));
+            }
+            latest = latest.max(transaction.timestamp);
+            match transaction.tx_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    if transaction.amount.0 < 0 {
+                        problems.push(Validation::NegativeAmount(row.line, *transaction));
+                    } else if transaction.amount.0 == 0 {
+                        problems.push(Validation::ZeroAmount(row.line, *transaction));
+                    }
+                    if !seen.insert(transaction.tx) {
+                        problems.push(Validation::DuplicateTransaction(row.line, *transaction));
+                    } else if transaction.tx_type == TransactionType::Deposit
+                        && transaction.amount.0 >= 0
+                    {
+                        deposits.insert(transaction.tx, transaction.client);
+                    }
+                }
+                TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback => {
+                    if row.has_amount {
+                        problems.push(Validation::UnexpectedAmount(row.line, *transaction));
+                    }
+                    if deposits.get(&transaction.tx) != Some(&transaction.client) {
+                        problems.push(Validation::UnknownTransaction(row.line, *transaction));
+                    }
+                }
+            }
+        }
+    });
+
+    problems
+}
+
+pub fn validate_file_from_path(path: &str) -> Result<Vec<Validation>, Csv> {
+    reader::read_rows_from_path(path).map(|rows| validate_rows(&rows))
+}
+
+/*
+ * Counts per kind of problem, in the order they're declared in.
+ * */
+pub fn summarize(problems: &[Validation]) -> Vec<(&'static str, usize)> {
+    let mut summary = vec![
+        ("malformed rows", 0),
+        ("negative amounts", 0),
+        ("zero amounts", 0),
+        ("unexpected amounts", 0),
+        ("duplicate tx ids", 0),
+        ("unknown tx ids", 0),
+        ("out of order", 0),
+    ];
+    problems.iter().for_each(|problem| {
+        let index = match problem {
+            Validation::MalformedRow(..) => 0,
+            Validation::NegativeAmount(..) => 1,
+            Validation::ZeroAmount(..) => 2,
+            Validation::UnexpectedAmount(..) => 3,
+            Validation::DuplicateTransaction(..) => 4,
+            Validation::UnknownTransaction(..) => 5,
+            Validation::OutOfOrder(..) => 6,
+        };
+        summary[index].1 += 1;
+    });
+    summary
+}
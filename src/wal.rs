@@ -0,0 +1,411 @@
+use crate::account::{self, new_from_client_id, Account};
+use crate::checkpoint::Checkpoint;
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::ledger::{Config, Ledger};
+use crate::snapshot::{self, Snapshot};
+use crate::timestamp::Timestamp;
+use crate::transaction::{Transaction, TransactionType};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/*
+ * A ledger that survives being killed. Every transaction is applied, and then
+ * written to the end of a write-ahead log together with how it went and what
+ * the account looked like after, before `apply` returns. Whoever gets an
+ * answer from `apply` can be sure it's on disk, as far as the OS is concerned.
+ * `sync` goes all the way to the disk, which is a lot slower.
+ *
+ * Every so often, `checkpoint` writes a snapshot and empties the log. That
+ * happens by itself whenever the log grows past its limit, so it never takes
+ * more than that on disk, or to replay. Starting up again reads the snapshot,
+ * and applies whatever's in the log after it.
+ * Every record carries its position, so a record the snapshot already covers
+ * is skipped. Records can carry where in which input file the transaction
+ * ended as well, so that's known for whatever's in the ledger.
+ *
+ * The log starts with "TPESWAL\0" and a u32 version. Every record after that
+ * is a u32 length, a u32 CRC-32 of the payload, and the payload itself. The
+ * payload is 67 bytes, followed by a `Checkpoint` laid out like in a snapshot.
+ * Only logs of the current version are read. A last record that's cut short
+ * or doesn't match its checksum is where the process died while writing, and
+ * is thrown away, as it was never acknowledged. A damaged record anywhere
+ * else refuses to open the log.
+ * */
+
+const MAGIC: &[u8; 8] = b"TPESWAL\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 12;
+const PAYLOAD_SIZE: usize = 67;
+/* About 400000 records */
+pub const LOG_LIMIT: u64 = 64 << 20;
+/*
+ * How many records go to the log between two `sync`s of a whole file. If the
+ * machine goes down, only records since the last one can be lost, and as they
+ * carry where they were in the input, those rows are just read again.
+ * */
+pub const SYNC_EVERY: u64 = 1024;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/* What the 8 bits of every byte do to the CRC, worked out upfront */
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/* CRC-32 as used by zip and ethernet, a byte at a time */
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (crc >> 8) ^ CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize]
+    })
+}
+
+/*
+ * One transaction, and what came of it.
+ * */
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    pub position: u64,
+    pub transaction: Transaction,
+    pub accepted: bool,
+    pub account: Option<Account>,
+    pub input: Option<Checkpoint>,
+}
+
+fn type_byte(tx_type: TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+    }
+}
+
+pub fn encode(entry: &Entry) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(PAYLOAD_SIZE);
+    let transaction = &entry.transaction;
+    payload.extend_from_slice(&entry.position.to_le_bytes());
+    payload.push(type_byte(transaction.tx_type));
+    payload.extend_from_slice(&transaction.client.to_le_bytes());
+    payload.extend_from_slice(&transaction.tx.to_le_bytes());
+    payload.extend_from_slice(&transaction.amount.0.to_le_bytes());
+    payload.push(transaction.timestamp.is_some() as u8);
+    payload.extend_from_slice(&transaction.timestamp.map_or(0, |t| t.0).to_le_bytes());
+    payload.push(entry.accepted as u8);
+    payload.push(entry.account.is_some() as u8);
+    let account = entry
+        .account
+        .unwrap_or_else(|| new_from_client_id(transaction.client));
+    payload.extend_from_slice(&account.available().0.to_le_bytes());
+    payload.extend_from_slice(&account.held().0.to_le_bytes());
+    payload.extend_from_slice(&account.total().0.to_le_bytes());
+    payload.push(account.locked() as u8);
+    payload.extend_from_slice(&account.receivable().0.to_le_bytes());
+    payload.push(entry.input.is_some() as u8);
+    if let Some(input) = &entry.input {
+        payload.extend_from_slice(&input.offset.to_le_bytes());
+        payload.extend_from_slice(&input.rows.to_le_bytes());
+        payload.extend_from_slice(&input.fingerprint.to_le_bytes());
+        payload.extend_from_slice(&(input.source.len() as u32).to_le_bytes());
+        payload.extend_from_slice(input.source.as_bytes());
+    }
+
+    let mut record = Vec::with_capacity(8 + PAYLOAD_SIZE);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn decode_input(input: &[u8]) -> Option<Option<Checkpoint>> {
+    match input.first() {
+        Some(0) if input.len() == 1 => Some(None),
+        Some(1) if input.len() >= 25 => {
+            let length = u32::from_le_bytes(input[21..25].try_into().expect("4 bytes"));
+            if input.len() - 25 != length as usize {
+                return None;
+            }
+            Some(Some(Checkpoint {
+                offset: u64::from_le_bytes(input[1..9].try_into().expect("8 bytes")),
+                rows: u64::from_le_bytes(input[9..17].try_into().expect("8 bytes")),
+                fingerprint: u32::from_le_bytes(input[17..21].try_into().expect("4 bytes")),
+                source: String::from_utf8(input[25..].to_vec()).ok()?.into(),
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn decode_payload(payload: &[u8]) -> Option<Entry> {
+    let input = decode_input(payload.get(PAYLOAD_SIZE..)?)?;
+    let u64_at = |at: usize| u64::from_le_bytes(payload[at..at + 8].try_into().expect("8 bytes"));
+    let i64_at = |at: usize| i64::from_le_bytes(payload[at..at + 8].try_into().expect("8 bytes"));
+    let client = u16::from_le_bytes([payload[9], payload[10]]);
+    let transaction = Transaction {
+        tx_type: match payload[8] {
+            0 => TransactionType::Deposit,
+            1 => TransactionType::Withdrawal,
+            2 => TransactionType::Dispute,
+            3 => TransactionType::Resolve,
+            4 => TransactionType::Chargeback,
+            _ => return None,
+        },
+        client,
+        tx: u32::from_le_bytes(payload[11..15].try_into().expect("4 bytes")),
+        amount: Currency(i64_at(15)),
+        timestamp: match payload[23] {
+            0 => None,
+            _ => Some(Timestamp(u64_at(24))),
+        },
+    };
+    Some(Entry {
+        position: u64_at(0),
+        transaction,
+        accepted: payload[32] == 1,
+        account: match payload[33] {
+            0 => None,
+            _ => Some(
+                account::restore(
+                    client,
+                    Currency(i64_at(34)),
+                    Currency(i64_at(42)),
+                    Currency(i64_at(59)),
+                    payload[58] == 1,
+                )
+                .filter(|account| account.total() == Currency(i64_at(50)))?,
+            ),
+        },
+        input,
+    })
+}
+
+/*
+ * Every intact entry after the header, and how many bytes they take up. Only
+ * the last record can be cut short or damaged, that's where a write got
+ * interrupted. Anywhere else, or a record that checks out but makes no sense,
+ * means the log itself is broken and nothing after it can be trusted.
+ * */
+pub fn decode(bytes: &[u8]) -> io::Result<(Vec<Entry>, u64)> {
+    if bytes.len() < HEADER_SIZE as usize || &bytes[..8] != MAGIC {
+        return Err(invalid("not a write-ahead log".to_string()));
+    }
+    match u32::from_le_bytes(bytes[8..12].try_into().expect("4 bytes")) {
+        VERSION => (),
+        other => {
+            return Err(invalid(format!(
+                "write-ahead log version {} can't be read, only {}",
+                other, VERSION
+            )))
+        }
+    }
+
+    let mut entries = vec![];
+    let mut at = HEADER_SIZE as usize;
+    while bytes.len() >= at + 8 {
+        let length = u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes")) as usize;
+        let checksum = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().expect("4 bytes"));
+        let payload = match bytes.get(at + 8..at + 8 + length) {
+            Some(payload) if crc32(payload) == checksum => payload,
+            Some(_) if at + 8 + length < bytes.len() => {
+                return Err(invalid(format!("write-ahead log is damaged at {}", at)))
+            }
+            _ => break,
+        };
+        match decode_payload(payload) {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(invalid(format!(
+                    "write-ahead log has a record at {} that can't be read",
+                    at
+                )))
+            }
+        }
+        at += 8 + length;
+    }
+    Ok((entries, at as u64))
+}
+
+pub struct DurableLedger {
+    ledger: Ledger,
+    log: File,
+    snapshot_path: String,
+    replayed: u64,
+    input: Option<Checkpoint>,
+    /* How big the log is, and how big it gets before it's checkpointed */
+    log_size: u64,
+    log_limit: u64,
+}
+
+impl DurableLedger {
+    /*
+     * Picks up from the snapshot and the log at these paths, if they're there.
+     * A log that doesn't agree with the ledger it's applied to means the
+     * config changed in between, and is refused.
+     * */
+    pub fn open(snapshot_path: &str, log_path: &str, config: Config) -> io::Result<DurableLedger> {
+        let (mut ledger, mut input) = match snapshot::read(snapshot_path)? {
+            Some(snapshot) => (Ledger::from_state(config, snapshot.state), snapshot.input),
+            None => (Ledger::new(config), None),
+        };
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_path)?;
+        let mut bytes = vec![];
+        log.read_to_end(&mut bytes)?;
+        /* Dying while writing the header leaves a piece of one */
+        if bytes.len() < HEADER_SIZE as usize && header().starts_with(&bytes) {
+            bytes = header();
+            log.set_len(0)?;
+            log.seek(SeekFrom::Start(0))?;
+            log.write_all(&bytes)?;
+        }
+
+        let (entries, intact) = decode(&bytes)?;
+        let start = ledger.position();
+        let mut replayed = 0;
+        for entry in entries.iter().filter(|entry| entry.position >= start) {
+            if entry.position != ledger.position() {
+                return Err(invalid(format!(
+                    "write-ahead log skips from {} to {}",
+                    ledger.position(),
+                    entry.position
+                )));
+            }
+            let accepted = ledger.apply(&entry.transaction).is_ok();
+            ledger.take_warnings();
+            let account = ledger.account(entry.transaction.client);
+            replayed += 1;
+            if entry.input.is_some() {
+                input = entry.input.clone();
+            }
+            if accepted != entry.accepted || account != entry.account {
+                return Err(invalid(format!(
+                    "write-ahead log doesn't match the ledger at {}, was the config changed?",
+                    entry.position
+                )));
+            }
+        }
+        log.set_len(intact)?;
+        log.seek(SeekFrom::End(0))?;
+
+        Ok(DurableLedger {
+            ledger,
+            log,
+            snapshot_path: snapshot_path.to_string(),
+            replayed,
+            input,
+            log_size: intact,
+            log_limit: LOG_LIMIT,
+        })
+    }
+
+    /* How many bytes the log can take before it's checkpointed */
+    pub fn set_log_limit(&mut self, bytes: u64) {
+        self.log_limit = bytes;
+    }
+
+    /* How many rows of the input went through, so where to continue from */
+    pub fn position(&self) -> u64 {
+        self.ledger.position()
+    }
+
+    /* How many transactions came from the log on top of the snapshot */
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /* How far into which input the ledger got, if that was ever given */
+    pub fn input(&self) -> Option<&Checkpoint> {
+        self.input.as_ref()
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<TransactionError> {
+        self.ledger.take_warnings()
+    }
+
+    /*
+     * The outer error is the log failing, or the checkpoint that comes after
+     * it when the log's full. The transaction is applied by then, but not
+     * acknowledged, so the ledger can't be trusted anymore and has to be
+     * opened again. `input` is where the transaction ended in its file, if it
+     * came from one, and goes into the same record.
+     * */
+    pub fn apply(
+        &mut self,
+        transaction: &Transaction,
+        input: Option<Checkpoint>,
+    ) -> io::Result<Result<(), TransactionError>> {
+        let position = self.ledger.position();
+        let result = self.ledger.apply(transaction);
+        let record = encode(&Entry {
+            position,
+            transaction: *transaction,
+            accepted: result.is_ok(),
+            account: self.ledger.account(transaction.client),
+            input: input.clone(),
+        });
+        self.log.write_all(&record)?;
+        self.log_size += record.len() as u64;
+        if input.is_some() {
+            self.input = input;
+        }
+        if self.log_size >= self.log_limit {
+            self.checkpoint()?;
+        }
+        Ok(result)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.sync_data()
+    }
+
+    /*
+     * The snapshot goes first. Dying before the log is emptied leaves entries
+     * the snapshot already has, which are skipped next time.
+     * */
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        snapshot::write(
+            &self.snapshot_path,
+            &Snapshot {
+                state: self.ledger.state(),
+                input: self.input.clone(),
+            },
+        )?;
+        self.log.set_len(0)?;
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.write_all(&header())?;
+        self.log_size = HEADER_SIZE;
+        Ok(())
+    }
+}
+
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header
+}
@@ -0,0 +1,132 @@
+use crate::account::Account;
+use crate::currency::{to_float_string, Currency};
+use crate::error::TransactionError;
+use crate::ledger::{Config, OverdraftPolicy};
+use crate::transaction::TransactionType;
+use serde::Serialize;
+use std::io;
+
+/*
+ * Columns that only go in the output when they're asked for, after the five
+ * every output has. Whoever doesn't ask gets exactly what they always got,
+ * down to there not being a header when there are no accounts.
+ * */
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Column {
+    /* What the client owes, see `OverdraftPolicy::Receivable` */
+    Receivable,
+    /* The client's credit line, and how much of it is drawn */
+    CreditLimit,
+    CreditUsed,
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::Receivable => "receivable",
+            Column::CreditLimit => "credit_limit",
+            Column::CreditUsed => "credit_used",
+        }
+    }
+
+    fn value(&self, account: &Account, config: &Config) -> Field {
+        match self {
+            Column::Receivable => Field::Amount(account.receivable()),
+            Column::CreditLimit => Field::Amount(config.client(account.client()).credit_limit),
+            Column::CreditUsed => Field::Amount(account.credit_used()),
+        }
+    }
+}
+
+/* Whatever the config calls for */
+pub fn columns(config: &Config) -> Vec<Column> {
+    let mut columns = vec![];
+    if config.overdraft == OverdraftPolicy::Receivable {
+        columns.push(Column::Receivable);
+    }
+    if !config.clients.is_empty() {
+        columns.extend([Column::CreditLimit, Column::CreditUsed]);
+    }
+    columns
+}
+
+/*
+ * A row is a list of these, as there's no struct that has just the columns
+ * asked for. Untagged, so every field comes out as its plain value.
+ * */
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Field {
+    Client(u16),
+    Amount(#[serde(serialize_with = "to_float_string")] Currency),
+    Locked(bool),
+}
+
+pub fn write_accounts<W: io::Write>(
+    accounts: &[Account],
+    columns: &[Column],
+    config: &Config,
+    writer: W,
+) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    let mut header = vec!["client", "available", "held", "total", "locked"];
+    header.extend(columns.iter().map(Column::name));
+    if !accounts.is_empty() {
+        writer.write_record(&header)?;
+    }
+    for account in accounts {
+        let mut row = vec![
+            Field::Client(account.client()),
+            Field::Amount(account.available()),
+            Field::Amount(account.held()),
+            Field::Amount(account.total()),
+            Field::Locked(account.locked()),
+        ];
+        row.extend(columns.iter().map(|column| column.value(account, config)));
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Review<'a> {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(serialize_with = "to_float_string")]
+    amount: Currency,
+    rule: &'a str,
+    reason: &'a str,
+}
+
+/*
+ * The transactions the rules flagged, with the rule and its reason, so they
+ * can be gone through by hand. Anything that isn't `Flagged` is left out. The
+ * header is always there, so an empty review still says what it would hold.
+ * */
+pub fn write_review<W: io::Write>(flagged: &[TransactionError], writer: W) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let mut any = false;
+    for flag in flagged {
+        if let TransactionError::Flagged(transaction, rule, reason) = flag {
+            writer.serialize(Review {
+                tx_type: transaction.tx_type,
+                client: transaction.client,
+                tx: transaction.tx,
+                amount: transaction.amount,
+                rule,
+                reason,
+            })?;
+            any = true;
+        }
+    }
+    if !any {
+        writer.write_record(["type", "client", "tx", "amount", "rule", "reason"])?;
+    }
+    writer.flush()?;
+    Ok(())
+}